@@ -0,0 +1,19 @@
+use std::process::Command;
+
+fn main() {
+    // Git revision the firmware is built from, reported via the Device
+    // Information Service
+    let git_hash = Command::new("git")
+        .args(["describe", "--always", "--dirty", "--abbrev=8"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=ROVER_HW_REV");
+    println!("cargo:rerun-if-env-changed=ROVER_PASSKEY");
+}
@@ -3,8 +3,20 @@ MEMORY
     /* Requirements for SoftDevice S112 v7.3.0:
      * 100kB (0x19000 bytes) of flash
      * at least 3.7kB (0xEB8 bytes) of RAM, actual value determined during
-     * runtime test.
+     * runtime test. The ATT MTU of 247 configured in SoftDevice::init()
+     * needs more than the default, 0x2200 isn't measured yet: set it to
+     * the value SoftDevice::init() logs.
+     * Keep the RAM origin in sync with APP_RAM_BASE in soft_device.rs.
+     * The last flash pages hold the fault log (see fault_log.rs), the
+     * settings (see settings_store.rs) and the bonds (see security.rs).
      */
-    FLASH : ORIGIN = 0x0000000 + 0x19000, LENGTH = 256K - 0x19000
-    RAM : ORIGIN = 0x20000000 + 0x1AE0, LENGTH = 32K - 0x1AE0
+    FLASH : ORIGIN = 0x0000000 + 0x19000, LENGTH = 256K - 0x19000 - 24K
+    FAULTS : ORIGIN = 256K - 24K, LENGTH = 8K
+    SETTINGS : ORIGIN = 256K - 16K, LENGTH = 8K
+    BONDS : ORIGIN = 256K - 8K, LENGTH = 8K
+    RAM : ORIGIN = 0x20000000 + 0x2200, LENGTH = 32K - 0x2200
 }
+
+__faults_start = ORIGIN(FAULTS);
+__settings_start = ORIGIN(SETTINGS);
+__bonds_start = ORIGIN(BONDS);
@@ -0,0 +1,160 @@
+//! Encoding of advertising and scan response payloads.
+//!
+//! A payload is a sequence of AD structures, each consisting of a length
+//! byte, the AD type and the data. The builder methods are `const fn`, so a
+//! payload built in a `const` or `static` initializer which doesn't fit into
+//! `MAX_LEN` bytes fails to compile:
+//!
+//! ```ignore
+//! static ADV_DATA: AdvertisingData = AdvertisingData::new()
+//!     .flags(FLAG_LE_GENERAL_DISCOVERABLE | FLAG_BR_EDR_NOT_SUPPORTED)
+//!     .short_name("Rusty");
+//! ```
+
+/// Maximum length of legacy advertising and scan response data
+pub const MAX_LEN: usize = 31;
+
+pub const FLAG_LE_LIMITED_DISCOVERABLE: u8 = 1 << 0;
+pub const FLAG_LE_GENERAL_DISCOVERABLE: u8 = 1 << 1;
+pub const FLAG_BR_EDR_NOT_SUPPORTED: u8 = 1 << 2;
+
+// AD types from the Bluetooth SIG assigned numbers
+const AD_FLAGS: u8 = 0x01;
+const AD_INCOMPLETE_16BIT_UUIDS: u8 = 0x02;
+const AD_COMPLETE_16BIT_UUIDS: u8 = 0x03;
+const AD_INCOMPLETE_128BIT_UUIDS: u8 = 0x06;
+const AD_COMPLETE_128BIT_UUIDS: u8 = 0x07;
+const AD_SHORT_NAME: u8 = 0x08;
+const AD_COMPLETE_NAME: u8 = 0x09;
+const AD_TX_POWER: u8 = 0x0A;
+const AD_APPEARANCE: u8 = 0x19;
+const AD_MANUFACTURER_DATA: u8 = 0xFF;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct AdvertisingData {
+    buf: [u8; MAX_LEN],
+    len: usize,
+}
+
+impl AdvertisingData {
+    pub const fn new() -> AdvertisingData {
+        AdvertisingData {
+            buf: [0; MAX_LEN],
+            len: 0,
+        }
+    }
+
+    /// See the `FLAG_*` constants.
+    pub const fn flags(self, flags: u8) -> Self {
+        self.header(AD_FLAGS, 1).byte(flags)
+    }
+
+    pub const fn short_name(self, name: &str) -> Self {
+        self.header(AD_SHORT_NAME, name.len())
+            .bytes(name.as_bytes())
+    }
+
+    pub const fn complete_name(self, name: &str) -> Self {
+        self.header(AD_COMPLETE_NAME, name.len())
+            .bytes(name.as_bytes())
+    }
+
+    /// Adds 16 bit service UUIDs. `complete` tells the scanner whether the
+    /// device has further services.
+    pub const fn services_16(self, complete: bool, uuids: &[u16]) -> Self {
+        let ad_type = if complete {
+            AD_COMPLETE_16BIT_UUIDS
+        } else {
+            AD_INCOMPLETE_16BIT_UUIDS
+        };
+        let mut data = self.header(ad_type, 2 * uuids.len());
+        let mut i = 0;
+        while i < uuids.len() {
+            data = data.u16(uuids[i]);
+            i += 1;
+        }
+        data
+    }
+
+    /// Adds 128 bit service UUIDs, given in little endian byte order like
+    /// `ble_uuid128_t`.
+    pub const fn services_128(self, complete: bool, uuids: &[[u8; 16]]) -> Self {
+        let ad_type = if complete {
+            AD_COMPLETE_128BIT_UUIDS
+        } else {
+            AD_INCOMPLETE_128BIT_UUIDS
+        };
+        let mut data = self.header(ad_type, 16 * uuids.len());
+        let mut i = 0;
+        while i < uuids.len() {
+            data = data.bytes(&uuids[i]);
+            i += 1;
+        }
+        data
+    }
+
+    pub const fn tx_power(self, dbm: i8) -> Self {
+        self.header(AD_TX_POWER, 1).byte(dbm as u8)
+    }
+
+    pub const fn appearance(self, appearance: u16) -> Self {
+        self.header(AD_APPEARANCE, 2).u16(appearance)
+    }
+
+    pub const fn manufacturer_data(self, company_id: u16, data: &[u8]) -> Self {
+        self.header(AD_MANUFACTURER_DATA, 2 + data.len())
+            .u16(company_id)
+            .bytes(data)
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Pointer for `ble_data_t`. The SoftDevice only reads through it.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.buf.as_mut_ptr()
+    }
+
+    const fn header(self, ad_type: u8, data_len: usize) -> Self {
+        if self.len + 2 + data_len > MAX_LEN {
+            panic!("Advertising data exceeds 31 bytes");
+        }
+        // The length covers the AD type and the data
+        self.byte(data_len as u8 + 1).byte(ad_type)
+    }
+
+    const fn byte(mut self, byte: u8) -> Self {
+        self.buf[self.len] = byte;
+        self.len += 1;
+        self
+    }
+
+    const fn u16(self, value: u16) -> Self {
+        let bytes = value.to_le_bytes();
+        self.byte(bytes[0]).byte(bytes[1])
+    }
+
+    const fn bytes(mut self, bytes: &[u8]) -> Self {
+        let mut i = 0;
+        while i < bytes.len() {
+            self = self.byte(bytes[i]);
+            i += 1;
+        }
+        self
+    }
+}
+
+impl Default for AdvertisingData {
+    fn default() -> Self {
+        AdvertisingData::new()
+    }
+}
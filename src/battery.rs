@@ -0,0 +1,80 @@
+//! Battery voltage measurement and state of charge estimation.
+//!
+//! The battery is connected to an analog input through a resistor divider.
+//! The SAADC has to be configured to match `SAADC_FULL_SCALE_MV` and
+//! `SAADC_RESOLUTION_BITS`, i.e. internal 0.6V reference, gain 1/6 and
+//! 12 bit resolution.
+
+pub const SAADC_FULL_SCALE_MV: u32 = 3600;
+pub const SAADC_RESOLUTION_BITS: u32 = 12;
+
+/// Open circuit voltage per cell in mV and the corresponding state of charge
+/// in percent, ordered from full to empty.
+pub type DischargeCurve = [(u16, u8)];
+
+pub const LI_ION_CURVE: &DischargeCurve = &[
+    (4200, 100),
+    (4100, 90),
+    (4000, 78),
+    (3900, 64),
+    (3800, 50),
+    (3700, 35),
+    (3600, 20),
+    (3500, 10),
+    (3300, 0),
+];
+
+pub const NIMH_CURVE: &DischargeCurve = &[
+    (1400, 100),
+    (1300, 90),
+    (1250, 75),
+    (1200, 50),
+    (1150, 25),
+    (1100, 10),
+    (1000, 0),
+];
+
+#[derive(Clone, Copy)]
+pub struct BatteryConfig {
+    /// Battery voltage = voltage at the pin * `divider_num / divider_den`
+    pub divider_num: u16,
+    pub divider_den: u16,
+    /// Number of cells in series
+    pub cells: u8,
+    pub curve: &'static DischargeCurve,
+}
+
+impl BatteryConfig {
+    /// Converts a raw SAADC sample to the battery voltage in mV.
+    pub fn battery_mv(&self, sample: i16) -> u16 {
+        // Noise can make single ended samples slightly negative
+        let sample = sample.max(0) as u32;
+        let pin_mv = (sample * SAADC_FULL_SCALE_MV) >> SAADC_RESOLUTION_BITS;
+        let battery_mv = pin_mv * self.divider_num as u32 / self.divider_den as u32;
+        battery_mv.min(u16::MAX as u32) as u16
+    }
+
+    /// Estimates the state of charge in percent by interpolating the
+    /// discharge curve.
+    pub fn percent(&self, battery_mv: u16) -> u8 {
+        let cell_mv = battery_mv / self.cells.max(1) as u16;
+        let (mut upper_mv, mut upper_percent) = match self.curve.first() {
+            Some(&point) => point,
+            None => return 0,
+        };
+        if cell_mv >= upper_mv {
+            return upper_percent;
+        }
+        for &(lower_mv, lower_percent) in &self.curve[1..] {
+            if cell_mv >= lower_mv {
+                let span_percent = (upper_percent - lower_percent) as u32;
+                let offset =
+                    (cell_mv - lower_mv) as u32 * span_percent / (upper_mv - lower_mv) as u32;
+                return lower_percent + offset as u8;
+            }
+            upper_mv = lower_mv;
+            upper_percent = lower_percent;
+        }
+        0
+    }
+}
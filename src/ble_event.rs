@@ -0,0 +1,376 @@
+//! Safe view of the events returned by `sd_ble_evt_get()`.
+//!
+//! `ble_evt_t` is a tagged union in C. `BleEvent::decode()` does the unsafe
+//! union accesses based on the event ID once, so event handlers can match
+//! on typed variants instead.
+
+use crate::connection::{ConnParams, PeerAddr, Phy, Role};
+use crate::gatt::Uuid;
+use nrf_softdevice_s112 as sd;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum BleEvent<'a> {
+    // Common events
+    UserMemRequest {
+        conn_handle: u16,
+    },
+    UserMemRelease {
+        conn_handle: u16,
+    },
+
+    // GAP events
+    Connected {
+        conn_handle: u16,
+        peer: PeerAddr,
+        role: Role,
+        params: ConnParams,
+    },
+    Disconnected {
+        conn_handle: u16,
+        /// HCI status code, e.g. `BLE_HCI_REMOTE_USER_TERMINATED_CONNECTION`
+        reason: u8,
+    },
+    ConnParamUpdate {
+        conn_handle: u16,
+        params: ConnParams,
+    },
+    ConnSecUpdate {
+        conn_handle: u16,
+        /// Security Mode and Level of the link, see `SecurityMode`
+        sec_mode: u8,
+        level: u8,
+        key_size: u8,
+    },
+    SecParamsRequest {
+        conn_handle: u16,
+    },
+    /// The central wants to encrypt the link with a key from an earlier
+    /// bonding, identified by EDIV and Rand (legacy) or its address (LESC).
+    SecInfoRequest {
+        conn_handle: u16,
+        peer: PeerAddr,
+        ediv: u16,
+        rand: [u8; 8],
+    },
+    SecRequest {
+        conn_handle: u16,
+    },
+    PasskeyDisplay {
+        conn_handle: u16,
+        /// ASCII digits
+        passkey: [u8; 6],
+        match_request: bool,
+    },
+    KeyPressed {
+        conn_handle: u16,
+    },
+    AuthKeyRequest {
+        conn_handle: u16,
+    },
+    AuthStatus {
+        conn_handle: u16,
+        /// `BLE_GAP_SEC_STATUS_*`
+        status: u8,
+        bonded: bool,
+        lesc: bool,
+    },
+    LescDhkeyRequest {
+        conn_handle: u16,
+    },
+    /// The central wants to change the PHY, the preferences are bit masks
+    /// of `BLE_GAP_PHY_*`.
+    PhyRequest {
+        conn_handle: u16,
+        tx_phys: u8,
+        rx_phys: u8,
+    },
+    PhyUpdate {
+        conn_handle: u16,
+        /// HCI status code
+        status: u8,
+        tx_phy: Phy,
+        rx_phy: Phy,
+    },
+    DataLengthRequest {
+        conn_handle: u16,
+        max_tx_octets: u16,
+        max_rx_octets: u16,
+    },
+    DataLengthUpdate {
+        conn_handle: u16,
+        max_tx_octets: u16,
+        max_rx_octets: u16,
+    },
+    RssiChanged {
+        conn_handle: u16,
+        rssi: i8,
+    },
+    AdvSetTerminated {
+        adv_handle: u8,
+        /// `BLE_GAP_EVT_ADV_SET_TERMINATED_REASON_*`
+        reason: u8,
+    },
+    ScanReqReport,
+    GapTimeout {
+        conn_handle: u16,
+        /// `BLE_GAP_TIMEOUT_SRC_*`
+        src: u8,
+    },
+
+    // GATT server events
+    Write {
+        conn_handle: u16,
+        /// Attribute handle, e.g. `Characteristic::value_handle`
+        handle: u16,
+        /// Type of the attribute, tells writes to attributes the
+        /// SoftDevice added itself apart
+        uuid: Uuid,
+        /// `BLE_GATTS_OP_*`
+        op: u8,
+        offset: u16,
+        data: &'a [u8],
+    },
+    MtuRequest {
+        conn_handle: u16,
+        client_mtu: u16,
+    },
+    HvnTxComplete {
+        conn_handle: u16,
+        count: u8,
+    },
+    Hvc {
+        conn_handle: u16,
+        handle: u16,
+    },
+    SysAttrMissing {
+        conn_handle: u16,
+    },
+    RwAuthorizeRequest {
+        conn_handle: u16,
+    },
+    ScConfirm {
+        conn_handle: u16,
+    },
+    GattsTimeout {
+        conn_handle: u16,
+    },
+
+    /// GATT client events, and anything this module doesn't know about
+    Other {
+        evt_id: u16,
+    },
+}
+
+impl<'a> BleEvent<'a> {
+    /// Decodes an event returned by `sd_ble_evt_get()`. For writes, the
+    /// data following `ble_evt_t` in the event buffer is borrowed, so the
+    /// buffer has to be as long as `header.evt_len`.
+    pub fn decode(evt: &'a sd::ble_evt_t) -> BleEvent<'a> {
+        let evt_id = evt.header.evt_id as u32;
+        match evt_id {
+            sd::BLE_EVT_BASE..=sd::BLE_EVT_LAST => {
+                let common_evt = unsafe { evt.evt.common_evt.as_ref() };
+                Self::decode_common(evt_id, common_evt)
+            }
+            sd::BLE_GAP_EVT_BASE..=sd::BLE_GAP_EVT_LAST => {
+                let gap_evt = unsafe { evt.evt.gap_evt.as_ref() };
+                Self::decode_gap(evt_id, gap_evt)
+            }
+            sd::BLE_GATTS_EVT_BASE..=sd::BLE_GATTS_EVT_LAST => {
+                let gatts_evt = unsafe { evt.evt.gatts_evt.as_ref() };
+                Self::decode_gatts(evt_id, gatts_evt)
+            }
+            _ => BleEvent::Other {
+                evt_id: evt_id as u16,
+            },
+        }
+    }
+
+    fn decode_common(evt_id: u32, evt: &sd::ble_common_evt_t) -> BleEvent<'a> {
+        let conn_handle = evt.conn_handle;
+        match evt_id {
+            sd::BLE_COMMON_EVTS_BLE_EVT_USER_MEM_REQUEST => {
+                BleEvent::UserMemRequest { conn_handle }
+            }
+            sd::BLE_COMMON_EVTS_BLE_EVT_USER_MEM_RELEASE => {
+                BleEvent::UserMemRelease { conn_handle }
+            }
+            _ => BleEvent::Other {
+                evt_id: evt_id as u16,
+            },
+        }
+    }
+
+    fn decode_gap(evt_id: u32, evt: &sd::ble_gap_evt_t) -> BleEvent<'a> {
+        let conn_handle = evt.conn_handle;
+        // Each arm only accesses the union member matching the event ID
+        unsafe {
+            match evt_id {
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_CONNECTED => {
+                    let connected = evt.params.connected.as_ref();
+                    BleEvent::Connected {
+                        conn_handle,
+                        peer: PeerAddr::from_raw(&connected.peer_addr),
+                        role: match connected.role as u32 {
+                            sd::BLE_GAP_ROLE_CENTRAL => Role::Central,
+                            _ => Role::Peripheral,
+                        },
+                        params: ConnParams::from_raw(&connected.conn_params),
+                    }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => BleEvent::Disconnected {
+                    conn_handle,
+                    reason: evt.params.disconnected.as_ref().reason,
+                },
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_CONN_PARAM_UPDATE => BleEvent::ConnParamUpdate {
+                    conn_handle,
+                    params: ConnParams::from_raw(
+                        &evt.params.conn_param_update.as_ref().conn_params,
+                    ),
+                },
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_CONN_SEC_UPDATE => {
+                    let conn_sec = evt.params.conn_sec_update.as_ref().conn_sec;
+                    BleEvent::ConnSecUpdate {
+                        conn_handle,
+                        sec_mode: conn_sec.sec_mode.sm(),
+                        level: conn_sec.sec_mode.lv(),
+                        key_size: conn_sec.encr_key_size,
+                    }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_SEC_PARAMS_REQUEST => {
+                    BleEvent::SecParamsRequest { conn_handle }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_SEC_INFO_REQUEST => {
+                    let request = evt.params.sec_info_request.as_ref();
+                    BleEvent::SecInfoRequest {
+                        conn_handle,
+                        peer: PeerAddr::from_raw(&request.peer_addr),
+                        ediv: request.master_id.ediv,
+                        rand: request.master_id.rand,
+                    }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_SEC_REQUEST => BleEvent::SecRequest { conn_handle },
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_PASSKEY_DISPLAY => {
+                    let display = evt.params.passkey_display.as_ref();
+                    BleEvent::PasskeyDisplay {
+                        conn_handle,
+                        passkey: display.passkey,
+                        match_request: display.match_request() != 0,
+                    }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_KEY_PRESSED => BleEvent::KeyPressed { conn_handle },
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_AUTH_KEY_REQUEST => {
+                    BleEvent::AuthKeyRequest { conn_handle }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_AUTH_STATUS => {
+                    let auth_status = evt.params.auth_status.as_ref();
+                    BleEvent::AuthStatus {
+                        conn_handle,
+                        status: auth_status.auth_status,
+                        bonded: auth_status.bonded() != 0,
+                        lesc: auth_status.lesc() != 0,
+                    }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_LESC_DHKEY_REQUEST => {
+                    BleEvent::LescDhkeyRequest { conn_handle }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_PHY_UPDATE_REQUEST => {
+                    let peer_preferred = evt.params.phy_update_request.as_ref().peer_preferred_phys;
+                    BleEvent::PhyRequest {
+                        conn_handle,
+                        tx_phys: peer_preferred.tx_phys,
+                        rx_phys: peer_preferred.rx_phys,
+                    }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_PHY_UPDATE => {
+                    let update = evt.params.phy_update.as_ref();
+                    BleEvent::PhyUpdate {
+                        conn_handle,
+                        status: update.status,
+                        tx_phy: Phy::from_raw(update.tx_phy),
+                        rx_phy: Phy::from_raw(update.rx_phy),
+                    }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_DATA_LENGTH_UPDATE_REQUEST => {
+                    let params = evt.params.data_length_update_request.as_ref().peer_params;
+                    BleEvent::DataLengthRequest {
+                        conn_handle,
+                        max_tx_octets: params.max_tx_octets,
+                        max_rx_octets: params.max_rx_octets,
+                    }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_DATA_LENGTH_UPDATE => {
+                    let params = evt.params.data_length_update.as_ref().effective_params;
+                    BleEvent::DataLengthUpdate {
+                        conn_handle,
+                        max_tx_octets: params.max_tx_octets,
+                        max_rx_octets: params.max_rx_octets,
+                    }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_RSSI_CHANGED => BleEvent::RssiChanged {
+                    conn_handle,
+                    rssi: evt.params.rssi_changed.as_ref().rssi,
+                },
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_ADV_SET_TERMINATED => {
+                    let terminated = evt.params.adv_set_terminated.as_ref();
+                    BleEvent::AdvSetTerminated {
+                        adv_handle: terminated.adv_handle,
+                        reason: terminated.reason,
+                    }
+                }
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_SCAN_REQ_REPORT => BleEvent::ScanReqReport,
+                sd::BLE_GAP_EVTS_BLE_GAP_EVT_TIMEOUT => BleEvent::GapTimeout {
+                    conn_handle,
+                    src: evt.params.timeout.as_ref().src,
+                },
+                _ => BleEvent::Other {
+                    evt_id: evt_id as u16,
+                },
+            }
+        }
+    }
+
+    fn decode_gatts(evt_id: u32, evt: &'a sd::ble_gatts_evt_t) -> BleEvent<'a> {
+        let conn_handle = evt.conn_handle;
+        // Each arm only accesses the union member matching the event ID
+        unsafe {
+            match evt_id {
+                sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_WRITE => {
+                    let write = evt.params.write.as_ref();
+                    BleEvent::Write {
+                        conn_handle,
+                        handle: write.handle,
+                        uuid: Uuid::from_raw(&write.uuid),
+                        op: write.op,
+                        offset: write.offset,
+                        // The data follows the event struct
+                        data: write.data.as_slice(write.len as usize),
+                    }
+                }
+                sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_EXCHANGE_MTU_REQUEST => BleEvent::MtuRequest {
+                    conn_handle,
+                    client_mtu: evt.params.exchange_mtu_request.as_ref().client_rx_mtu,
+                },
+                sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_HVN_TX_COMPLETE => BleEvent::HvnTxComplete {
+                    conn_handle,
+                    count: evt.params.hvn_tx_complete.as_ref().count,
+                },
+                sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_HVC => BleEvent::Hvc {
+                    conn_handle,
+                    handle: evt.params.hvc.as_ref().handle,
+                },
+                sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_SYS_ATTR_MISSING => {
+                    BleEvent::SysAttrMissing { conn_handle }
+                }
+                sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_RW_AUTHORIZE_REQUEST => {
+                    BleEvent::RwAuthorizeRequest { conn_handle }
+                }
+                sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_SC_CONFIRM => BleEvent::ScConfirm { conn_handle },
+                sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_TIMEOUT => BleEvent::GattsTimeout { conn_handle },
+                _ => BleEvent::Other {
+                    evt_id: evt_id as u16,
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Keys of bonded centrals and their encoding in flash.
+//!
+//! Each bond is stored as a fixed size record of `Bond::WORDS` words. A
+//! record starts with `Bond::MARKER`, so erased flash (all `0xFF`) ends the
+//! list.
+//!
+//! The records follow a page header of `PAGE_MAGIC` and a sequence number,
+//! see `flash::page_seq()`.
+//! Bonds are saved to the other of two pages with the header written last,
+//! so the page with the highest sequence number is always complete.
+
+use heapless::Vec;
+
+/// Bonds kept at most, the oldest one is replaced when bonding with another
+/// central.
+pub const MAX_BONDS: usize = 8;
+pub const PAGE_MAGIC: u32 = 0x424F_4E44; // "BOND"
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Bond {
+    /// Identity address of the central, `BLE_GAP_ADDR_TYPE_*` and address
+    pub addr_type: u8,
+    pub addr: [u8; 6],
+    /// Identity Resolving Key of the central, all zeros if not distributed
+    pub irk: [u8; 16],
+    /// Long Term Key used to encrypt the link when reconnecting
+    pub ltk: [u8; 16],
+    pub ltk_len: u8,
+    /// Key was generated by LE Secure Connections pairing
+    pub lesc: bool,
+    /// Key was generated with MITM protection
+    pub auth: bool,
+    /// Identifies the key in legacy pairing, both 0 for LESC
+    pub ediv: u16,
+    pub rand: [u8; 8],
+}
+
+impl Bond {
+    pub const MARKER: u8 = 0xB1;
+    pub const LEN: usize = 52;
+    pub const WORDS: usize = Bond::LEN / 4;
+
+    /// Encodes the bond as flash record:
+    ///
+    /// | Offset | Size | Content                      |
+    /// |--------|------|------------------------------|
+    /// | 0      | 1    | `MARKER`                     |
+    /// | 1      | 1    | Address type                 |
+    /// | 2      | 6    | Address                      |
+    /// | 8      | 16   | IRK                          |
+    /// | 24     | 16   | LTK                          |
+    /// | 40     | 1    | LTK length                   |
+    /// | 41     | 1    | Bit 0: LESC, bit 1: MITM     |
+    /// | 42     | 2    | EDIV, little endian          |
+    /// | 44     | 8    | Rand                         |
+    pub fn encode(&self) -> [u8; Bond::LEN] {
+        let mut buf = [0u8; Bond::LEN];
+        buf[0] = Bond::MARKER;
+        buf[1] = self.addr_type;
+        buf[2..8].copy_from_slice(&self.addr);
+        buf[8..24].copy_from_slice(&self.irk);
+        buf[24..40].copy_from_slice(&self.ltk);
+        buf[40] = self.ltk_len;
+        buf[41] = self.lesc as u8 | (self.auth as u8) << 1;
+        buf[42..44].copy_from_slice(&self.ediv.to_le_bytes());
+        buf[44..52].copy_from_slice(&self.rand);
+        buf
+    }
+
+    /// Returns `None` if `buf` doesn't start with a valid record.
+    pub fn decode(buf: &[u8]) -> Option<Bond> {
+        if buf.len() < Bond::LEN || buf[0] != Bond::MARKER {
+            return None;
+        }
+        let mut bond = Bond {
+            addr_type: buf[1],
+            addr: [0; 6],
+            irk: [0; 16],
+            ltk: [0; 16],
+            ltk_len: buf[40],
+            lesc: buf[41] & 1 != 0,
+            auth: buf[41] & 2 != 0,
+            ediv: u16::from_le_bytes([buf[42], buf[43]]),
+            rand: [0; 8],
+        };
+        bond.addr.copy_from_slice(&buf[2..8]);
+        bond.irk.copy_from_slice(&buf[8..24]);
+        bond.ltk.copy_from_slice(&buf[24..40]);
+        bond.rand.copy_from_slice(&buf[44..52]);
+        Some(bond)
+    }
+}
+
+/// Bonds ordered from oldest to newest.
+#[derive(Default)]
+pub struct BondStore {
+    bonds: Vec<Bond, MAX_BONDS>,
+}
+
+impl BondStore {
+    pub const fn new() -> BondStore {
+        BondStore { bonds: Vec::new() }
+    }
+
+    /// Reads the records written by `encode()`, e.g. from flash.
+    pub fn decode(buf: &[u8]) -> BondStore {
+        let mut store = BondStore::new();
+        for record in buf.chunks(Bond::LEN).take(MAX_BONDS) {
+            match Bond::decode(record) {
+                // Can't fail, at most MAX_BONDS records are read
+                Some(bond) => store.bonds.push(bond).ok(),
+                None => break,
+            };
+        }
+        store
+    }
+
+    /// Encodes all bonds into `words`, returns the number of words used.
+    pub fn encode(&self, words: &mut [u32; MAX_BONDS * Bond::WORDS]) -> usize {
+        for (bond, record) in self.bonds.iter().zip(words.chunks_mut(Bond::WORDS)) {
+            let bytes = bond.encode();
+            for (word, chunk) in record.iter_mut().zip(bytes.chunks(4)) {
+                *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            }
+        }
+        self.bonds.len() * Bond::WORDS
+    }
+
+    /// Adds a bond, replacing one with the same identity address or the
+    /// oldest one if the store is full.
+    pub fn insert(&mut self, bond: Bond) {
+        let replaced = self
+            .bonds
+            .iter()
+            .position(|b| b.addr == bond.addr && b.addr_type == bond.addr_type);
+        let removed = match replaced {
+            Some(i) => Some(i),
+            None if self.bonds.is_full() => Some(0),
+            None => None,
+        };
+        if let Some(i) = removed {
+            // Keeps the order from oldest to newest
+            self.bonds[i..].rotate_left(1);
+            self.bonds.pop();
+        }
+        // Can't fail, there's room now
+        self.bonds.push(bond).ok();
+    }
+
+    pub fn find_by_addr(&self, addr_type: u8, addr: &[u8; 6]) -> Option<&Bond> {
+        self.bonds
+            .iter()
+            .find(|b| b.addr_type == addr_type && &b.addr == addr)
+    }
+
+    /// Looks up a legacy pairing key by its EDIV and Rand.
+    pub fn find_by_master_id(&self, ediv: u16, rand: &[u8; 8]) -> Option<&Bond> {
+        self.bonds
+            .iter()
+            .find(|b| !b.lesc && b.ediv == ediv && &b.rand == rand)
+    }
+
+    pub fn clear(&mut self) {
+        self.bonds.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Bond> {
+        self.bonds.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.bonds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bonds.is_empty()
+    }
+}
@@ -0,0 +1,144 @@
+//! Protocol of the configuration characteristic.
+//!
+//! The central writes a command, the rover executes it and notifies the
+//! reply on the same characteristic:
+//!
+//! | Command          | Request                     | Reply value       |
+//! |------------------|-----------------------------|-------------------|
+//! | `Get`            | `0x01`, key                 | value of the key  |
+//! | `Set`            | `0x02`, key, value          | -                 |
+//! | `Commit`         | `0x03`                      | -                 |
+//! | `ResetDefaults`  | `0x04`                      | -                 |
+//!
+//! Replies start with the opcode of the command and a `Status`, followed by
+//! the value for `Get`. Keys and value encodings are those of
+//! `settings::Key` and `Settings::get()`. `Set` changes the setting right
+//! away, `Commit` stores all settings in flash. `ResetDefaults` does both
+//! for the default values.
+
+use crate::settings::{Key, SettingsError, MAX_VALUE_LEN};
+use heapless::Vec;
+
+/// Opcode, key and value
+pub const MAX_COMMAND_LEN: usize = 2 + MAX_VALUE_LEN;
+/// Opcode, status and value
+pub const MAX_REPLY_LEN: usize = 2 + MAX_VALUE_LEN;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Opcode {
+    Get = 1,
+    Set = 2,
+    Commit = 3,
+    ResetDefaults = 4,
+}
+
+impl Opcode {
+    pub fn from_raw(opcode: u8) -> Option<Opcode> {
+        [
+            Opcode::Get,
+            Opcode::Set,
+            Opcode::Commit,
+            Opcode::ResetDefaults,
+        ]
+        .iter()
+        .copied()
+        .find(|op| *op as u8 == opcode)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum Status {
+    Success = 0,
+    UnknownCommand = 1,
+    UnknownKey = 2,
+    InvalidLength = 3,
+    OutOfRange = 4,
+    /// Writing to flash failed, the settings are applied but not stored
+    StorageFailed = 5,
+    /// A previous command is still being executed
+    Busy = 6,
+}
+
+impl From<SettingsError> for Status {
+    fn from(e: SettingsError) -> Status {
+        match e {
+            SettingsError::UnknownKey => Status::UnknownKey,
+            SettingsError::InvalidLength => Status::InvalidLength,
+            SettingsError::OutOfRange => Status::OutOfRange,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub enum Command {
+    Get(Key),
+    Set(Key, Vec<u8, MAX_VALUE_LEN>),
+    Commit,
+    ResetDefaults,
+}
+
+impl Command {
+    /// Parses a command written by the central. On error, returns the
+    /// reply to send instead of executing it.
+    pub fn parse(data: &[u8]) -> Result<Command, Reply> {
+        let (&opcode, args) = data
+            .split_first()
+            .ok_or_else(|| Reply::new(0, Status::InvalidLength))?;
+        let error = |status| Reply::new(opcode, status);
+        let key = |key: u8| Key::from_raw(key).ok_or_else(|| error(Status::UnknownKey));
+        match (Opcode::from_raw(opcode), args) {
+            (Some(Opcode::Get), &[k]) => Ok(Command::Get(key(k)?)),
+            (Some(Opcode::Set), &[k, ref value @ ..]) => {
+                let key = key(k)?;
+                let value = Vec::from_slice(value).map_err(|_| error(Status::InvalidLength))?;
+                Ok(Command::Set(key, value))
+            }
+            (Some(Opcode::Commit), &[]) => Ok(Command::Commit),
+            (Some(Opcode::ResetDefaults), &[]) => Ok(Command::ResetDefaults),
+            (Some(_), _) => Err(error(Status::InvalidLength)),
+            (None, _) => Err(error(Status::UnknownCommand)),
+        }
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Command::Get(_) => Opcode::Get,
+            Command::Set(..) => Opcode::Set,
+            Command::Commit => Opcode::Commit,
+            Command::ResetDefaults => Opcode::ResetDefaults,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    buf: [u8; MAX_REPLY_LEN],
+    len: usize,
+}
+
+impl Reply {
+    /// `opcode` is echoed from the command, it's a raw byte to allow
+    /// replying to unknown ones.
+    pub fn new(opcode: u8, status: Status) -> Reply {
+        let mut buf = [0; MAX_REPLY_LEN];
+        buf[0] = opcode;
+        buf[1] = status as u8;
+        Reply { buf, len: 2 }
+    }
+
+    /// Successful reply to `Get`.
+    pub fn with_value(value: &[u8]) -> Reply {
+        let mut reply = Reply::new(Opcode::Get as u8, Status::Success);
+        reply.buf[2..2 + value.len()].copy_from_slice(value);
+        reply.len += value.len();
+        reply
+    }
+
+    pub fn status(&self) -> u8 {
+        self.buf[1]
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
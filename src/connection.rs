@@ -0,0 +1,143 @@
+//! State of the connection to the central, maintained from GAP events.
+
+use nrf_softdevice_s112 as sd;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum AddrType {
+    Public,
+    RandomStatic,
+    RandomPrivateResolvable,
+    RandomPrivateNonResolvable,
+    Anonymous,
+    Unknown(u8),
+}
+
+impl AddrType {
+    pub fn from_raw(addr_type: u8) -> AddrType {
+        match addr_type as u32 {
+            sd::BLE_GAP_ADDR_TYPE_PUBLIC => AddrType::Public,
+            sd::BLE_GAP_ADDR_TYPE_RANDOM_STATIC => AddrType::RandomStatic,
+            sd::BLE_GAP_ADDR_TYPE_RANDOM_PRIVATE_RESOLVABLE => AddrType::RandomPrivateResolvable,
+            sd::BLE_GAP_ADDR_TYPE_RANDOM_PRIVATE_NON_RESOLVABLE => {
+                AddrType::RandomPrivateNonResolvable
+            }
+            sd::BLE_GAP_ADDR_TYPE_ANONYMOUS => AddrType::Anonymous,
+            other => AddrType::Unknown(other as u8),
+        }
+    }
+
+    pub fn raw(self) -> u8 {
+        (match self {
+            AddrType::Public => sd::BLE_GAP_ADDR_TYPE_PUBLIC,
+            AddrType::RandomStatic => sd::BLE_GAP_ADDR_TYPE_RANDOM_STATIC,
+            AddrType::RandomPrivateResolvable => sd::BLE_GAP_ADDR_TYPE_RANDOM_PRIVATE_RESOLVABLE,
+            AddrType::RandomPrivateNonResolvable => {
+                sd::BLE_GAP_ADDR_TYPE_RANDOM_PRIVATE_NON_RESOLVABLE
+            }
+            AddrType::Anonymous => sd::BLE_GAP_ADDR_TYPE_ANONYMOUS,
+            AddrType::Unknown(other) => other as u32,
+        }) as u8
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct PeerAddr {
+    pub addr_type: AddrType,
+    /// Little endian, i.e. reversed compared to the usual notation
+    pub addr: [u8; 6],
+}
+
+impl PeerAddr {
+    pub fn from_raw(addr: &sd::ble_gap_addr_t) -> PeerAddr {
+        PeerAddr {
+            addr_type: AddrType::from_raw(addr.addr_type()),
+            addr: addr.addr,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Role {
+    Peripheral,
+    Central,
+}
+
+/// Negotiated connection parameters, in the units used on air.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ConnParams {
+    /// In units of 1.25 ms
+    pub interval: u16,
+    /// Number of connection events the peripheral may skip
+    pub latency: u16,
+    /// Supervision timeout in units of 10 ms
+    pub timeout: u16,
+}
+
+impl ConnParams {
+    /// Takes the connection parameters of a GAP event. Those contain the
+    /// actual interval as both minimum and maximum.
+    pub fn from_raw(params: &sd::ble_gap_conn_params_t) -> ConnParams {
+        ConnParams {
+            interval: params.max_conn_interval,
+            latency: params.slave_latency,
+            timeout: params.conn_sup_timeout,
+        }
+    }
+
+    pub fn interval_us(&self) -> u32 {
+        self.interval as u32 * 1250
+    }
+
+    pub fn timeout_ms(&self) -> u32 {
+        self.timeout as u32 * 10
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Phy {
+    OneMbps,
+    TwoMbps,
+    Coded,
+    Unknown(u8),
+}
+
+impl Phy {
+    pub fn from_raw(phy: u8) -> Phy {
+        match phy as u32 {
+            sd::BLE_GAP_PHY_1MBPS => Phy::OneMbps,
+            sd::BLE_GAP_PHY_2MBPS => Phy::TwoMbps,
+            sd::BLE_GAP_PHY_CODED => Phy::Coded,
+            other => Phy::Unknown(other as u8),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Connection {
+    pub handle: u16,
+    pub peer: PeerAddr,
+    pub role: Role,
+    pub params: ConnParams,
+    /// ATT MTU in bytes, notifications carry up to `att_mtu - 3` bytes
+    pub att_mtu: u16,
+    pub tx_phy: Phy,
+    pub rx_phy: Phy,
+    /// Security Mode 1 level, 1 = no encryption
+    pub security_level: u8,
+}
+
+impl Connection {
+    /// State right after connecting, before any MTU exchange or PHY update.
+    pub fn new(handle: u16, peer: PeerAddr, role: Role, params: ConnParams) -> Connection {
+        Connection {
+            handle,
+            peer,
+            role,
+            params,
+            att_mtu: sd::BLE_GATT_ATT_MTU_DEFAULT as u16,
+            tx_phy: Phy::OneMbps,
+            rx_phy: Phy::OneMbps,
+            security_level: 1,
+        }
+    }
+}
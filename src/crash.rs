@@ -0,0 +1,145 @@
+//! Faults which reset the rover, kept in RAM until they're logged.
+//!
+//! Panics, HardFaults and SoftDevice faults store a `Fault` in the `.uninit`
+//! section, which survives a reset as it's neither zeroed nor initialized
+//! at startup. The next boot takes it with `take()`, and it's written to
+//! the `FaultLog` once the SoftDevice allows flash operations. A CRC tells
+//! a stored fault from the random contents after power-up.
+//!
+//! Resets after faults are counted there as well, so a fault which happens
+//! right after every boot can be detected, see `fault_resets()`.
+
+use crate::fault_log::{Fault, FaultKind};
+use crate::settings::crc32;
+use core::mem::MaybeUninit;
+use core::ptr;
+
+/// `POWER.RESETREAS` bits
+pub const RESET_WATCHDOG: u32 = 1 << 1;
+pub const RESET_SOFT: u32 = 1 << 2;
+pub const RESET_LOCKUP: u32 = 1 << 3;
+
+const MAGIC: u32 = 0xC4A5_4ED0;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Stored {
+    magic: u32,
+    crc: u32,
+    record: [u8; Fault::LEN],
+}
+
+#[link_section = ".uninit.rusty_rover.crash"]
+static mut STORED: MaybeUninit<Stored> = MaybeUninit::uninit();
+
+// Count and its complement
+#[link_section = ".uninit.rusty_rover.fault_resets"]
+static mut FAULT_RESETS: MaybeUninit<[u32; 2]> = MaybeUninit::uninit();
+
+/// Stores a fault for the next boot. If one is stored already, it's kept,
+/// as the first fault is likely the cause of the others.
+pub fn record(fault: &Fault) {
+    cortex_m::interrupt::free(|_| unsafe {
+        if stored().is_some() {
+            return;
+        }
+        let record = fault.encode();
+        ptr::write_volatile(
+            STORED.as_mut_ptr(),
+            Stored {
+                magic: MAGIC,
+                crc: crc32(&record),
+                record,
+            },
+        );
+    })
+}
+
+/// Stores a panic with its message and location.
+pub fn record_panic(info: &core::panic::PanicInfo) {
+    let mut fault = match info.location() {
+        Some(location) => Fault::with_data(FaultKind::Panic, &[location.line(), location.column()]),
+        None => Fault::new(FaultKind::Panic),
+    };
+    fault.set_text(format_args!("{}", info));
+    record(&fault);
+}
+
+/// Stores a HardFault with the registers stacked on exception entry.
+pub fn record_hard_fault(frame: &cortex_m_rt::ExceptionFrame) {
+    record(&Fault::with_data(
+        FaultKind::HardFault,
+        &[
+            frame.r0(),
+            frame.r1(),
+            frame.r2(),
+            frame.r3(),
+            frame.r12(),
+            frame.lr(),
+            frame.pc(),
+            frame.xpsr(),
+        ],
+    ));
+}
+
+/// Takes the fault stored before the last reset, with `reset_reason` (the
+/// value of `POWER.RESETREAS`) added. Resets by the watchdog or a lockup
+/// without a stored fault are reported as `FaultKind::Reset`.
+pub fn take(reset_reason: u32) -> Option<Fault> {
+    let fault = cortex_m::interrupt::free(|_| unsafe {
+        let fault = stored();
+        ptr::write_volatile(ptr::addr_of_mut!((*STORED.as_mut_ptr()).magic), 0);
+        fault
+    });
+    match fault {
+        Some(fault) => Some(Fault {
+            reset_reason,
+            ..fault
+        }),
+        None if reset_reason & (RESET_WATCHDOG | RESET_LOCKUP) != 0 => Some(Fault {
+            reset_reason,
+            ..Fault::new(FaultKind::Reset)
+        }),
+        None => None,
+    }
+}
+
+unsafe fn stored() -> Option<Fault> {
+    let stored = ptr::read_volatile(STORED.as_ptr());
+    if stored.magic != MAGIC || crc32(&stored.record) != stored.crc {
+        return None;
+    }
+    Fault::decode(&stored.record)
+}
+
+/// Resets the chip after a fault was stored, counting it as fault reset.
+pub fn reset() -> ! {
+    count_fault_reset();
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Counts a reset which didn't go through `reset()`, e.g. by the watchdog
+/// or a CPU lockup.
+pub fn count_fault_reset() {
+    cortex_m::interrupt::free(|_| unsafe {
+        let count = fault_resets().saturating_add(1);
+        ptr::write_volatile(FAULT_RESETS.as_mut_ptr(), [count, !count]);
+    });
+}
+
+/// Number of fault resets since the count was cleared, or since
+/// power-up.
+pub fn fault_resets() -> u32 {
+    let [count, check] = unsafe { ptr::read_volatile(FAULT_RESETS.as_ptr()) };
+    if check == !count {
+        count
+    } else {
+        0
+    }
+}
+
+/// Clears the count of fault resets, e.g. after running without fault for
+/// a while.
+pub fn clear_fault_resets() {
+    unsafe { ptr::write_volatile(FAULT_RESETS.as_mut_ptr(), [0, !0]) };
+}
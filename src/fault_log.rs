@@ -0,0 +1,343 @@
+//! Persistent log of faults which stopped or reset the rover, e.g. power
+//! failures and panics.
+//!
+//! Faults are appended to one of the two `FAULTS` flash pages (see
+//! memory.x) as fixed size records, following a page header of `PAGE_MAGIC`
+//! and a sequence number. A record starts with `Fault::MARKER`, so erased
+//! flash ends the log. When the page is full, the newest `MAX_FAULTS`
+//! faults are written to the other page with the header written last, so
+//! a reset meanwhile doesn't lose the log. Faults which reset the rover
+//! reach the log via `crash`.
+
+use crate::flash::{self, FlashError, PAGE_HEADER_LEN, PAGE_SIZE};
+use core::fmt::{self, Write};
+use heapless::Vec;
+
+/// Faults kept in RAM, and in flash after the page was full
+pub const MAX_FAULTS: usize = 16;
+pub const DATA_WORDS: usize = 8;
+pub const TEXT_LEN: usize = 56;
+pub const PAGE_MAGIC: u32 = 0x4641_554C; // "FAUL"
+
+const PAGES: usize = 2;
+
+extern "C" {
+    // Defined in memory.x
+    static __faults_start: u32;
+}
+
+fn page_addr(page: usize) -> u32 {
+    unsafe { &__faults_start as *const u32 as u32 + page as u32 * PAGE_SIZE }
+}
+
+fn page_bytes(page: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(page_addr(page) as *const u8, PAGE_SIZE as usize) }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FaultKind {
+    /// Supply voltage dropped below the power failure threshold, logged
+    /// once it recovered. Data: number of warnings until then
+    PowerFailure,
+    /// SoftDevice assertion, data: ID, PC and info of `nrf_fault_handler`
+    SoftDevice,
+    /// Data: line and column, text: panic message and location
+    Panic,
+    /// Data: stacked R0-R3, R12, LR, PC and xPSR
+    HardFault,
+    /// Reset by the watchdog or a lockup without a fault stored before
+    Reset,
+    /// Watchdog timeout, data: `WDT.REQSTATUS`, the reload registers which
+    /// weren't written in time
+    Watchdog,
+    /// Written by a newer firmware
+    Unknown(u8),
+}
+
+impl FaultKind {
+    pub fn from_raw(kind: u8) -> FaultKind {
+        match kind {
+            1 => FaultKind::PowerFailure,
+            2 => FaultKind::SoftDevice,
+            3 => FaultKind::Panic,
+            4 => FaultKind::HardFault,
+            5 => FaultKind::Reset,
+            6 => FaultKind::Watchdog,
+            other => FaultKind::Unknown(other),
+        }
+    }
+
+    pub fn raw(self) -> u8 {
+        match self {
+            FaultKind::PowerFailure => 1,
+            FaultKind::SoftDevice => 2,
+            FaultKind::Panic => 3,
+            FaultKind::HardFault => 4,
+            FaultKind::Reset => 5,
+            FaultKind::Watchdog => 6,
+            FaultKind::Unknown(other) => other,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    /// Increases with each fault, orders them across resets
+    pub seq: u16,
+    pub kind: FaultKind,
+    /// `POWER.RESETREAS` at the boot after the fault, 0 if it didn't reset
+    pub reset_reason: u32,
+    /// Depends on `kind`
+    pub data: [u32; DATA_WORDS],
+    /// UTF-8, padded with zeros
+    text: [u8; TEXT_LEN],
+}
+
+impl Fault {
+    pub const MARKER: u8 = 0xFB;
+    pub const LEN: usize = 8 + DATA_WORDS * 4 + TEXT_LEN;
+    pub const WORDS: usize = Fault::LEN / 4;
+
+    pub fn new(kind: FaultKind) -> Fault {
+        Fault {
+            seq: 0,
+            kind,
+            reset_reason: 0,
+            data: [0; DATA_WORDS],
+            text: [0; TEXT_LEN],
+        }
+    }
+
+    /// `data` is truncated to `DATA_WORDS`.
+    pub fn with_data(kind: FaultKind, data: &[u32]) -> Fault {
+        let mut fault = Fault::new(kind);
+        let len = data.len().min(DATA_WORDS);
+        fault.data[..len].copy_from_slice(&data[..len]);
+        fault
+    }
+
+    /// Replaces the text, truncated to `TEXT_LEN` bytes.
+    pub fn set_text(&mut self, args: fmt::Arguments) {
+        self.text = [0; TEXT_LEN];
+        let mut writer = TextWriter {
+            text: &mut self.text,
+            len: 0,
+        };
+        // Only fails when truncating
+        writer.write_fmt(args).ok();
+    }
+
+    pub fn text(&self) -> &str {
+        let len = self.text.iter().position(|b| *b == 0).unwrap_or(TEXT_LEN);
+        match core::str::from_utf8(&self.text[..len]) {
+            Ok(text) => text,
+            // Truncated within a character
+            Err(e) => core::str::from_utf8(&self.text[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+
+    /// Encodes the fault as flash record:
+    ///
+    /// | Offset | Size | Content                    |
+    /// |--------|------|----------------------------|
+    /// | 0      | 1    | `MARKER`                   |
+    /// | 1      | 1    | Kind                       |
+    /// | 2      | 2    | Sequence number            |
+    /// | 4      | 4    | Reset reason               |
+    /// | 8      | 32   | Data words                 |
+    /// | 40     | 56   | Text                       |
+    ///
+    /// All values are little endian.
+    pub fn encode(&self) -> [u8; Fault::LEN] {
+        let mut buf = [0u8; Fault::LEN];
+        buf[0] = Fault::MARKER;
+        buf[1] = self.kind.raw();
+        buf[2..4].copy_from_slice(&self.seq.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.reset_reason.to_le_bytes());
+        for (chunk, word) in buf[8..].chunks_mut(4).zip(self.data.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        buf[8 + DATA_WORDS * 4..].copy_from_slice(&self.text);
+        buf
+    }
+
+    /// Returns `None` if `buf` doesn't start with a valid record.
+    pub fn decode(buf: &[u8]) -> Option<Fault> {
+        if buf.len() < Fault::LEN || buf[0] != Fault::MARKER {
+            return None;
+        }
+        let word = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let mut fault = Fault::new(FaultKind::from_raw(buf[1]));
+        fault.seq = u16::from_le_bytes([buf[2], buf[3]]);
+        fault.reset_reason = word(4);
+        for (i, data) in fault.data.iter_mut().enumerate() {
+            *data = word(8 + i * 4);
+        }
+        fault
+            .text
+            .copy_from_slice(&buf[8 + DATA_WORDS * 4..Fault::LEN]);
+        Some(fault)
+    }
+
+    fn words(&self) -> [u32; Fault::WORDS] {
+        let bytes = self.encode();
+        let mut words = [0u32; Fault::WORDS];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+            *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        words
+    }
+}
+
+impl defmt::Format for Fault {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "#{} {}, reset reason 0x{:08x}, data {:08x}, \"{}\"",
+            self.seq,
+            self.kind,
+            self.reset_reason,
+            self.data,
+            self.text()
+        )
+    }
+}
+
+struct TextWriter<'a> {
+    text: &'a mut [u8; TEXT_LEN],
+    len: usize,
+}
+
+impl Write for TextWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(TEXT_LEN - self.len);
+        self.text[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        if len < s.len() {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct FaultLog {
+    // Oldest first
+    faults: Vec<Fault, MAX_FAULTS>,
+    // Page holding the records
+    page: usize,
+    seq: u32,
+    // Offset of the next record in the page, 0 if no page is in use yet
+    end: usize,
+    next_seq: u16,
+}
+
+impl FaultLog {
+    /// Reads the log from the current page in flash.
+    pub fn load() -> FaultLog {
+        let current = (0..PAGES)
+            .filter_map(|page| flash::page_seq(page_bytes(page), PAGE_MAGIC).map(|seq| (page, seq)))
+            .max_by_key(|(_, seq)| *seq);
+        match current {
+            Some((page, _)) => FaultLog {
+                page,
+                ..FaultLog::read(page_bytes(page))
+            },
+            None => FaultLog::read(&[]),
+        }
+    }
+
+    /// Reads the records of a fault log page, keeping the newest
+    /// `MAX_FAULTS`. A page without header, e.g. erased, holds none.
+    pub fn read(page: &[u8]) -> FaultLog {
+        let mut log = FaultLog {
+            faults: Vec::new(),
+            // The first record moves on to page 0
+            page: PAGES - 1,
+            seq: 0,
+            end: 0,
+            next_seq: 0,
+        };
+        log.seq = match flash::page_seq(page, PAGE_MAGIC) {
+            Some(seq) => seq,
+            None => return log,
+        };
+        log.end = PAGE_HEADER_LEN;
+        while let Some(fault) = Fault::decode(&page[log.end..]) {
+            log.push(fault);
+            log.end += Fault::LEN;
+        }
+        if matches!(page.get(log.end), Some(b) if *b != 0xFF) {
+            // Not a record we wrote, e.g. an older format. The page is
+            // erased with the next record.
+            log.end = page.len();
+        }
+        log
+    }
+
+    /// Appends a fault to the log in flash, assigning its sequence number.
+    /// Has to be called from a task, see `flash`.
+    pub fn record(&mut self, fault: Fault) -> Result<Fault, FlashError> {
+        let fault = Fault {
+            seq: self.next_seq,
+            ..fault
+        };
+        self.push(fault);
+        if self.end == 0 || self.end + Fault::LEN > PAGE_SIZE as usize {
+            self.compact()?;
+        } else {
+            flash::write(page_addr(self.page) + self.end as u32, &fault.words())?;
+            self.end += Fault::LEN;
+        }
+        Ok(fault)
+    }
+
+    /// Erases the log.
+    pub fn clear(&mut self) -> Result<(), FlashError> {
+        self.faults.clear();
+        self.compact()
+    }
+
+    /// Faults from oldest to newest.
+    pub fn iter(&self) -> core::slice::Iter<'_, Fault> {
+        self.faults.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.faults.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faults.is_empty()
+    }
+
+    fn push(&mut self, fault: Fault) {
+        if self.faults.is_full() {
+            self.faults.rotate_left(1);
+            self.faults.pop();
+        }
+        // Can't fail, there's room now
+        self.faults.push(fault).ok();
+        self.next_seq = fault.seq.wrapping_add(1);
+    }
+
+    /// Writes the faults in RAM to the other page. The page header is
+    /// written last, so the current page stays valid until the new one is
+    /// complete.
+    fn compact(&mut self) -> Result<(), FlashError> {
+        let page = (self.page + 1) % PAGES;
+        let seq = self.seq.wrapping_add(1);
+        flash::erase_page(page_addr(page))?;
+        let mut end = PAGE_HEADER_LEN;
+        for fault in self.faults.iter() {
+            flash::write(page_addr(page) + end as u32, &fault.words())?;
+            end += Fault::LEN;
+        }
+        flash::write(page_addr(page), &flash::page_header(PAGE_MAGIC, seq))?;
+
+        self.page = page;
+        self.seq = seq;
+        self.end = end;
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+//! Flash writes while the SoftDevice is enabled.
+//!
+//! The NVMC must not be used directly then, flash operations have to be
+//! requested from the SoftDevice, which runs them between radio events and
+//! reports completion as SoC event. The functions here wait for that event,
+//! so the data to write stays valid until the operation is done.
+//!
+//! The event reaches us through `soft_device::handle_soc_events()`, which
+//! has to run at a higher priority than the callers of these functions.
+//!
+//! Waiting blocks all other tasks at the caller's priority and below.
+//! Writes take well below a millisecond, but erasing a page takes tens of
+//! milliseconds and longer if the SoftDevice has to fit it between radio
+//! events. Tasks which must not be delayed that long, like the ones
+//! driving the motors, have to run at a higher priority, and resources
+//! shared with them must not be locked while waiting.
+
+use crate::soft_device::{SdError, SocEvent};
+use core::sync::atomic::{AtomicU8, Ordering};
+use nrf_softdevice_s112 as sd;
+
+// State of the current operation, updated from the SoC event handler
+const PENDING: u8 = 0;
+const SUCCEEDED: u8 = 1;
+const FAILED: u8 = 2;
+static STATE: AtomicU8 = AtomicU8::new(SUCCEEDED);
+
+pub const PAGE_SIZE: u32 = 4096;
+/// Page header: magic and sequence number, see `page_header()`
+pub const PAGE_HEADER_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum FlashError {
+    /// The SoftDevice rejected the request
+    Sd(SdError),
+    /// The operation didn't complete, e.g. due to radio activity
+    Failed,
+}
+
+/// Erases the flash page containing `addr`. Blocks the caller's priority
+/// for tens of milliseconds, see the module documentation.
+pub fn erase_page(addr: u32) -> Result<(), FlashError> {
+    run(|| unsafe { sd::sd_flash_page_erase(addr / PAGE_SIZE) })
+}
+
+/// Writes `words` to flash starting at the word aligned `addr`. The area
+/// must be erased.
+pub fn write(addr: u32, words: &[u32]) -> Result<(), FlashError> {
+    if words.is_empty() {
+        return Ok(());
+    }
+    run(|| unsafe { sd::sd_flash_write(addr as *mut u32, words.as_ptr(), words.len() as u32) })
+}
+
+fn run(op: impl Fn() -> u32) -> Result<(), FlashError> {
+    loop {
+        // Set before starting, the event may arrive before op() returns
+        STATE.store(PENDING, Ordering::SeqCst);
+        match SdError::check(op()) {
+            Ok(()) => break,
+            // Another flash operation is still running
+            Err(SdError::Busy) => continue,
+            Err(e) => return Err(FlashError::Sd(e)),
+        }
+    }
+    loop {
+        match STATE.load(Ordering::SeqCst) {
+            PENDING => continue,
+            SUCCEEDED => return Ok(()),
+            _ => return Err(FlashError::Failed),
+        }
+    }
+}
+
+/// Returns the sequence number of a page starting with `magic`, `None` if
+/// the page doesn't (e.g. erased or interrupted while saving).
+///
+/// Pages written in turns start with this header, written last, so the
+/// page with the highest sequence number is always complete.
+pub fn page_seq(page: &[u8], magic: u32) -> Option<u32> {
+    if page.len() < PAGE_HEADER_LEN {
+        return None;
+    }
+    let word = |i: usize| u32::from_le_bytes([page[i], page[i + 1], page[i + 2], page[i + 3]]);
+    match (word(0), word(4)) {
+        (m, seq) if m == magic && seq != 0xFFFF_FFFF => Some(seq),
+        _ => None,
+    }
+}
+
+/// Header words of a page, see `page_seq()`.
+pub fn page_header(magic: u32, seq: u32) -> [u32; PAGE_HEADER_LEN / 4] {
+    [magic, seq]
+}
+
+/// Completes the pending operation, see `soft_device::handle_soc_events()`.
+pub fn on_soc_event(event: SocEvent) {
+    match event {
+        SocEvent::FlashOperationSuccess => STATE.store(SUCCEEDED, Ordering::SeqCst),
+        SocEvent::FlashOperationError => STATE.store(FAILED, Ordering::SeqCst),
+        _ => (),
+    }
+}
@@ -0,0 +1,404 @@
+//! Builder for the GATT table, wrapping `sd_ble_gatts_service_add()` and
+//! `sd_ble_gatts_characteristic_add()` along with their metadata bitfields.
+//!
+//! ```ignore
+//! let service = Service::new(Uuid::Sig(0x180F))?;
+//! let level = service
+//!     .characteristic(Uuid::Sig(0x2A19))
+//!     .read()
+//!     .notify()
+//!     .value(&[100])
+//!     .register()?;
+//! ```
+
+use crate::soft_device::SdError;
+use nrf_softdevice_s112 as sd;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Uuid {
+    /// 16 bit UUID assigned by the Bluetooth SIG
+    Sig(u16),
+    /// Bytes 12 and 13 of a vendor specific 128 bit base UUID. `uuid_type`
+    /// is what `sd_ble_uuid_vs_add()` returned for the base UUID.
+    Vendor { uuid_type: u8, uuid: u16 },
+}
+
+impl Uuid {
+    pub fn from_raw(uuid: &sd::ble_uuid_t) -> Uuid {
+        match uuid.type_ as u32 {
+            sd::BLE_UUID_TYPE_BLE => Uuid::Sig(uuid.uuid),
+            _ => Uuid::Vendor {
+                uuid_type: uuid.type_,
+                uuid: uuid.uuid,
+            },
+        }
+    }
+
+    pub fn raw(&self) -> sd::ble_uuid_t {
+        match *self {
+            Uuid::Sig(uuid) => sd::ble_uuid_t {
+                type_: sd::BLE_UUID_TYPE_BLE as u8,
+                uuid,
+            },
+            Uuid::Vendor { uuid_type, uuid } => sd::ble_uuid_t {
+                type_: uuid_type,
+                uuid,
+            },
+        }
+    }
+}
+
+/// Full 128 bit UUID of `Uuid::Vendor`, i.e. `base` (little endian, as
+/// passed to `sd_ble_uuid_vs_add()`) with `uuid` in bytes 12 and 13.
+pub const fn uuid128(base: &[u8; 16], uuid: u16) -> [u8; 16] {
+    let mut full = *base;
+    let bytes = uuid.to_le_bytes();
+    full[12] = bytes[0];
+    full[13] = bytes[1];
+    full
+}
+
+/// Security required for accessing an attribute (GAP Security Mode 1
+/// levels, or no access at all).
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SecurityMode {
+    NoAccess,
+    /// No encryption required
+    Open,
+    /// Encrypted link, possibly without MITM protection
+    Encrypted,
+    /// Encrypted link with MITM protection, i.e. authenticated pairing
+    EncryptedMitm,
+    /// Like `EncryptedMitm`, but LE Secure Connections pairing is required
+    LescEncryptedMitm,
+}
+
+impl SecurityMode {
+    pub fn raw(self) -> sd::ble_gap_conn_sec_mode_t {
+        let (sm, lv) = match self {
+            SecurityMode::NoAccess => (0, 0),
+            SecurityMode::Open => (1, 1),
+            SecurityMode::Encrypted => (1, 2),
+            SecurityMode::EncryptedMitm => (1, 3),
+            SecurityMode::LescEncryptedMitm => (1, 4),
+        };
+        sd::ble_gap_conn_sec_mode_t {
+            _bitfield_1: sd::ble_gap_conn_sec_mode_t::new_bitfield_1(sm, lv),
+        }
+    }
+}
+
+/// A primary service in the GATT table.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Service {
+    handle: u16,
+}
+
+impl Service {
+    /// Adds a primary service to the GATT table. Characteristics added
+    /// afterwards via `characteristic()` belong to this service, so they
+    /// have to be added before the next service.
+    pub fn new(uuid: Uuid) -> Result<Service, SdError> {
+        let mut handle = 0;
+        SdError::check(unsafe {
+            sd::sd_ble_gatts_service_add(
+                sd::BLE_GATTS_SRVC_TYPE_PRIMARY as u8,
+                &uuid.raw(),
+                &mut handle,
+            )
+        })?;
+        Ok(Service { handle })
+    }
+
+    pub fn handle(&self) -> u16 {
+        self.handle
+    }
+
+    pub fn characteristic<'a>(&self, uuid: Uuid) -> CharacteristicBuilder<'a> {
+        CharacteristicBuilder::new(self.handle, uuid)
+    }
+}
+
+pub struct CharacteristicBuilder<'a> {
+    service_handle: u16,
+    uuid: Uuid,
+    read: bool,
+    write: bool,
+    write_without_response: bool,
+    reliable_write: bool,
+    notify: bool,
+    indicate: bool,
+    read_perm: SecurityMode,
+    write_perm: SecurityMode,
+    max_len: u16,
+    variable_len: bool,
+    value: &'a [u8],
+    user_desc: &'a str,
+}
+
+impl<'a> CharacteristicBuilder<'a> {
+    /// Usually created via `Service::characteristic()`.
+    pub fn new(service_handle: u16, uuid: Uuid) -> Self {
+        CharacteristicBuilder {
+            service_handle,
+            uuid,
+            read: false,
+            write: false,
+            write_without_response: false,
+            reliable_write: false,
+            notify: false,
+            indicate: false,
+            read_perm: SecurityMode::Open,
+            write_perm: SecurityMode::Open,
+            max_len: 0,
+            variable_len: false,
+            value: &[],
+            user_desc: "",
+        }
+    }
+
+    pub fn read(mut self) -> Self {
+        self.read = true;
+        self
+    }
+
+    pub fn write(mut self) -> Self {
+        self.write = true;
+        self
+    }
+
+    pub fn write_without_response(mut self) -> Self {
+        self.write_without_response = true;
+        self
+    }
+
+    /// Allows queued writes, which the central executes at once.
+    pub fn reliable_write(mut self) -> Self {
+        self.reliable_write = true;
+        self
+    }
+
+    pub fn notify(mut self) -> Self {
+        self.notify = true;
+        self
+    }
+
+    pub fn indicate(mut self) -> Self {
+        self.indicate = true;
+        self
+    }
+
+    /// Security required for reads, and for enabling notifications or
+    /// indications. Defaults to `SecurityMode::Open`.
+    pub fn read_perm(mut self, mode: SecurityMode) -> Self {
+        self.read_perm = mode;
+        self
+    }
+
+    /// Security required for writes. Defaults to `SecurityMode::Open`.
+    pub fn write_perm(mut self, mode: SecurityMode) -> Self {
+        self.write_perm = mode;
+        self
+    }
+
+    /// Maximum value length, defaults to the length of the initial value.
+    pub fn max_len(mut self, len: u16) -> Self {
+        self.max_len = len;
+        self
+    }
+
+    /// Allows values shorter than `max_len`.
+    pub fn variable_len(mut self) -> Self {
+        self.variable_len = true;
+        self
+    }
+
+    /// Initial value, the SoftDevice keeps a copy. Without one, the value
+    /// is initialized to `max_len` zeros.
+    pub fn value(mut self, value: &'a [u8]) -> Self {
+        self.value = value;
+        self
+    }
+
+    /// Adds a Characteristic User Description descriptor.
+    pub fn user_desc(mut self, desc: &'a str) -> Self {
+        self.user_desc = desc;
+        self
+    }
+
+    pub fn char_props(&self) -> sd::ble_gatt_char_props_t {
+        sd::ble_gatt_char_props_t {
+            _bitfield_1: sd::ble_gatt_char_props_t::new_bitfield_1(
+                0, // broadcast
+                self.read as u8,
+                self.write_without_response as u8,
+                self.write as u8,
+                self.notify as u8,
+                self.indicate as u8,
+                0, // auth_signed_wr
+            ),
+        }
+    }
+
+    pub fn char_ext_props(&self) -> sd::ble_gatt_char_ext_props_t {
+        sd::ble_gatt_char_ext_props_t {
+            _bitfield_1: sd::ble_gatt_char_ext_props_t::new_bitfield_1(
+                self.reliable_write as u8,
+                0, // wr_aux
+            ),
+        }
+    }
+
+    pub fn register(self) -> Result<Characteristic, SdError> {
+        let read_perm = if self.read {
+            self.read_perm
+        } else {
+            SecurityMode::NoAccess
+        };
+        let write_perm = if self.write || self.write_without_response || self.reliable_write {
+            self.write_perm
+        } else {
+            SecurityMode::NoAccess
+        };
+        let attr_md = attr_md(read_perm, write_perm, self.variable_len);
+        // Client Characteristic Configuration Descriptor, where the central
+        // enables notifications and indications
+        let cccd_md = attr_md_cccd(self.read_perm);
+
+        let char_md = sd::ble_gatts_char_md_t {
+            char_props: self.char_props(),
+            char_ext_props: self.char_ext_props(),
+            p_char_user_desc: if self.user_desc.is_empty() {
+                core::ptr::null()
+            } else {
+                self.user_desc.as_ptr()
+            },
+            char_user_desc_max_size: self.user_desc.len() as u16,
+            char_user_desc_size: self.user_desc.len() as u16,
+            p_char_pf: core::ptr::null(),
+            p_user_desc_md: core::ptr::null(),
+            p_cccd_md: if self.notify || self.indicate {
+                &cccd_md
+            } else {
+                core::ptr::null()
+            },
+            p_sccd_md: core::ptr::null(),
+        };
+
+        let max_len = self.max_len.max(self.value.len() as u16);
+        let uuid = self.uuid.raw();
+        let attr = sd::ble_gatts_attr_t {
+            p_uuid: &uuid,
+            p_attr_md: &attr_md,
+            init_len: if self.value.is_empty() {
+                max_len
+            } else {
+                self.value.len() as u16
+            },
+            init_offs: 0,
+            max_len,
+            // NULL initializes the value with zeros. Otherwise, the value is
+            // copied and never written through this pointer.
+            p_value: if self.value.is_empty() {
+                core::ptr::null_mut()
+            } else {
+                self.value.as_ptr() as *mut u8
+            },
+        };
+
+        let mut handles = sd::ble_gatts_char_handles_t {
+            value_handle: 0,
+            user_desc_handle: 0,
+            cccd_handle: 0,
+            sccd_handle: 0,
+        };
+        SdError::check(unsafe {
+            sd::sd_ble_gatts_characteristic_add(self.service_handle, &char_md, &attr, &mut handles)
+        })?;
+        Ok(Characteristic {
+            value_handle: handles.value_handle,
+            user_desc_handle: handles.user_desc_handle,
+            cccd_handle: handles.cccd_handle,
+        })
+    }
+}
+
+fn attr_md(
+    read_perm: SecurityMode,
+    write_perm: SecurityMode,
+    variable_len: bool,
+) -> sd::ble_gatts_attr_md_t {
+    sd::ble_gatts_attr_md_t {
+        read_perm: read_perm.raw(),
+        write_perm: write_perm.raw(),
+        _bitfield_1: sd::ble_gatts_attr_md_t::new_bitfield_1(
+            variable_len as u8,
+            sd::BLE_GATTS_VLOC_STACK as u8,
+            0, // rd_auth
+            0, // wr_auth
+        ),
+    }
+}
+
+fn attr_md_cccd(perm: SecurityMode) -> sd::ble_gatts_attr_md_t {
+    // The CCCD must always be readable
+    attr_md(SecurityMode::Open, perm, false)
+}
+
+/// Attribute handles of a registered characteristic, 0 if not present.
+#[derive(Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Characteristic {
+    pub value_handle: u16,
+    pub user_desc_handle: u16,
+    pub cccd_handle: u16,
+}
+
+impl Characteristic {
+    pub fn is_registered(&self) -> bool {
+        self.value_handle != 0
+    }
+
+    /// Reads the value into `buf`, returns the number of bytes read.
+    pub fn value(&self, conn_handle: u16, buf: &mut [u8]) -> Result<usize, SdError> {
+        let mut gatts_val = sd::ble_gatts_value_t {
+            len: buf.len() as u16,
+            offset: 0,
+            p_value: buf.as_mut_ptr(),
+        };
+        SdError::check(unsafe {
+            sd::sd_ble_gatts_value_get(conn_handle, self.value_handle, &mut gatts_val)
+        })?;
+        Ok((gatts_val.len as usize).min(buf.len()))
+    }
+
+    /// Updates the value without notifying the central.
+    pub fn set_value(&self, value: &[u8]) -> Result<(), SdError> {
+        let mut gatts_val = sd::ble_gatts_value_t {
+            len: value.len() as u16,
+            offset: 0,
+            p_value: value.as_ptr() as *mut u8,
+        };
+        SdError::check(unsafe {
+            sd::sd_ble_gatts_value_set(
+                sd::BLE_CONN_HANDLE_INVALID as u16,
+                self.value_handle,
+                &mut gatts_val,
+            )
+        })
+    }
+
+    /// Updates the value and sends a notification. Fails with
+    /// `SdError::InvalidState` or `SdError::GattsSysAttrMissing` if the
+    /// central didn't enable notifications.
+    pub fn notify(&self, conn_handle: u16, value: &[u8]) -> Result<(), SdError> {
+        let mut len = value.len() as u16;
+        let hvx_params = sd::ble_gatts_hvx_params_t {
+            handle: self.value_handle,
+            type_: sd::BLE_GATT_HVX_NOTIFICATION as u8,
+            offset: 0,
+            p_len: &mut len,
+            p_data: value.as_ptr(),
+        };
+        SdError::check(unsafe { sd::sd_ble_gatts_hvx(conn_handle, &hvx_params) })
+    }
+}
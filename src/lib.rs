@@ -5,17 +5,47 @@ use defmt_rtt as _; // global logger
 
 use nrf52832_hal as _; // memory layout
 
-use panic_probe as _;
-
+pub mod adv;
+pub mod battery;
+pub mod ble_event;
+pub mod bond;
+pub mod config;
+pub mod connection;
+pub mod crash;
+pub mod fault_log;
+pub mod flash;
+pub mod gatt;
+pub mod monotonic;
+pub mod motor;
+pub mod ramp;
+pub mod security;
+pub mod settings;
+pub mod settings_store;
 pub mod soft_device;
+pub mod telemetry;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    defmt::error!("{}", defmt::Display2Format(info));
+    crash::record_panic(info);
+    cortex_m::asm::udf()
+}
 
-// same panicking *behavior* as `panic-probe` but doesn't print a panic message
-// this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+// `defmt::panic!()` already printed its message
 #[defmt::panic_handler]
-fn panic() -> ! {
+fn defmt_panic() -> ! {
+    crash::record(&fault_log::Fault::new(fault_log::FaultKind::Panic));
     cortex_m::asm::udf()
 }
 
+// A debugger catches HardFaults before this runs
+#[cortex_m_rt::exception]
+unsafe fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    crash::record_hard_fault(frame);
+    // Logs the fault at the next boot
+    crash::reset()
+}
+
 /// Terminates the application and makes `probe-run` exit with exit-code = 0
 pub fn exit() -> ! {
     loop {
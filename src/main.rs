@@ -9,16 +9,59 @@ use rusty_rover as _; // global logger + panicking-behavior + memory layout
  * SWI2 for event notifications to our app
  * SWI4 for future use
  * SWI5 for internal use
- * This leaves us with SWI0, SWI3 and probably SWI1 for our use as
- * dispatchers.
+ * This leaves us with SWI0, SWI3 and, as radio notifications aren't
+ * enabled, SWI1 for our use as dispatchers.
  */
-#[app(device = nrf52832_hal::pac, dispatchers = [SWI0_EGU0])]
+#[app(device = nrf52832_hal::pac, dispatchers = [SWI0_EGU0, SWI1_EGU1, SWI3_EGU3])]
 mod app {
-    use dwt_systick_monotonic::{fugit::ExtU32, DwtSystick};
+    use heapless::String;
+    use nrf52832_hal::wdt::{count, handles::*, Watchdog, WatchdogHandle};
     use nrf52832_hal::{self as hal, gpio::*, prelude::*};
-    use rusty_rover::soft_device::SoftDevice;
+    use rusty_rover::battery::{BatteryConfig, LI_ION_CURVE};
+    use rusty_rover::config::{Command, Reply, Status};
+    use rusty_rover::crash;
+    use rusty_rover::fault_log::{Fault, FaultKind, FaultLog};
+    use rusty_rover::monotonic::{ExtU32, Rtc1Monotonic};
+    use rusty_rover::motor::{DualMotorDriver, Motor};
+    use rusty_rover::ramp::DualRamp;
+    use rusty_rover::settings::{Key, Settings, MAX_NAME_LEN, MAX_VALUE_LEN};
+    use rusty_rover::settings_store::SettingsStore;
+    use rusty_rover::soft_device::{self, FaultPolicy, SocEvent, SoftDevice};
+    use rusty_rover::telemetry::{Faults, Telemetry};
 
-    const F_CPU_HZ: u32 = 64_000_000;
+    // Number of attempts to initialize the SoftDevice after the first one
+    const SD_INIT_RETRIES: u8 = 3;
+    const SD_INIT_RETRY_DELAY_MS: u32 = 1000;
+    // Fast blinking LEDs signal an unrecoverable error, see blink()
+    const ERROR_BLINK_FREQ: u8 = 9;
+    // Signals a power failure, slower than errors
+    const POWER_FAILURE_BLINK_FREQ: u8 = 5;
+    // Boots in safe mode, without motors, after this many fault resets in a
+    // row. Running this long without a fault breaks the row.
+    const MAX_FAULT_RESETS: u32 = 3;
+    const FAULT_FREE_PERIOD_MS: u32 = 60_000;
+    // Resets unless every watched task checked in within this time, see
+    // watchdog()
+    const WDT_TIMEOUT_MS: u32 = 3000;
+    const WATCHDOG_PERIOD_MS: u32 = 1000;
+    // Speed changes are spread out over several steps, see rusty_rover::ramp
+    const RAMP_PERIOD_MS: u32 = 10;
+    // Settled ramps keep stepping this often to check in with the watchdog
+    const RAMP_IDLE_PERIOD_MS: u32 = WATCHDOG_PERIOD_MS;
+    const TELEMETRY_PERIOD_MS: u32 = 500;
+    const BATTERY_PERIOD_MS: u32 = 5000;
+    // 2S Li-ion pack on P0_29 (A5) through a 200k/100k divider
+    const BATTERY: BatteryConfig = BatteryConfig {
+        divider_num: 3,
+        divider_den: 1,
+        cells: 2,
+        curve: LI_ION_CURVE,
+    };
+    // Battery level in percent below which LOW_BATTERY is reported
+    const LOW_BATTERY_PERCENT: u8 = 10;
+    // Motors stay off this long after a power failure, long enough for a
+    // battery sample taken after it, see power_failure_rearm()
+    const POWER_FAILURE_HOLD_MS: u32 = 2 * BATTERY_PERIOD_MS;
     /* The NRF52832 has NVIC_PRIO_BITS = 3, so the RTIC task priorities
      * range from 1..8.
      * For the SoftDevice we have to reserve NVIC priorities 0, 1 and 4,
@@ -30,38 +73,141 @@ mod app {
      * One thing to keep in mind is we may only call to the SoftDevice
      * from NVIC priorities > 4, so RTIC priorities < 4, as our requests
      * will be handled in the SVC handler running at NVIC priority 4!
+     * Flash operations block the other priority 1 tasks while waiting for
+     * the SoftDevice, for tens of milliseconds during a page erase, see
+     * rusty_rover::flash. So the tasks driving the motors run at priority
+     * 3, above the flash operations and the SoftDevice events.
+     * Resources shared with them must not be locked around a flash
+     * operation.
      */
-    #[monotonic(binds = SysTick, default = true, priority = 6)]
-    type DwtMono = DwtSystick<F_CPU_HZ>;
+    // Keeps counting while idle() sleeps, unlike SysTick
+    #[monotonic(binds = RTC1, default = true, priority = 6)]
+    type RtcMono = Rtc1Monotonic;
+
+    #[derive(Clone, Copy, defmt::Format)]
+    pub enum FailsafeReason {
+        Disconnected,
+        Timeout,
+    }
+
+    type Motors = DualMotorDriver<
+        hal::pwm::Pwm<hal::pac::PWM0>,
+        Pin<Output<PushPull>>,
+        Pin<Output<PushPull>>,
+    >;
 
     #[shared]
     struct Shared {
         sd: SoftDevice,
         blink_freq: u8, // f = (blink_freq + 1)*.5Hz
+        motors: Motors,
+        ramps: DualRamp,
+        faults: Faults,
+        battery_mv: u16,
+        settings: Settings,
+        settings_store: SettingsStore,
+        fault_log: FaultLog,
+        // Next run of the settled ramp, see ramp()
+        ramp_idle: Option<ramp::SpawnHandle>,
+        // Power failure warnings not logged yet, see power_failure_rearm()
+        power_warnings: u32,
     }
     #[local]
     struct Local {
         led1: p0::P0_17<Output<PushPull>>,
         led2: p0::P0_19<Output<PushPull>>,
-        pwm: hal::pwm::Pwm<hal::pac::PWM0>,
-        motor_r_dir: Pin<Output<PushPull>>,
-        motor_l_dir: Pin<Output<PushPull>>,
+        saadc: hal::saadc::Saadc,
+        battery_pin: p0::P0_29<Input<Floating>>,
+        // One WDT reload register per watched task
+        wdt_sd_events: WatchdogHandle<Hdl0>,
+        wdt_motors: WatchdogHandle<Hdl1>,
+        wdt_timers: WatchdogHandle<Hdl2>,
+        // Fault which reset the rover, logged once flash can be written
+        reset_fault: Option<Fault>,
     }
 
     #[init]
     fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
         defmt::info!("HW initialization...");
-        let _hw_clocks = hal::clocks::Clocks::new(cx.device.CLOCK).enable_ext_hfosc();
-
-        let mut dcb = cx.core.DCB;
-        let dwt = cx.core.DWT;
-        let systick = cx.core.SYST;
-        let mono_clock = DwtSystick::new(&mut dcb, dwt, systick, F_CPU_HZ);
+        // The LFCLK source has to match the SoftDevice's configuration
+        let _hw_clocks = hal::clocks::Clocks::new(cx.device.CLOCK)
+            .enable_ext_hfosc()
+            .set_lfclk_src_external(hal::clocks::LfOscConfiguration::NoExternalNoBypass)
+            .start_lfclk();
+        let mono_clock = Rtc1Monotonic::new(cx.device.RTC1);
 
         let port0 = hal::gpio::p0::Parts::new(cx.device.P0);
         let led1 = port0.p0_17.into_push_pull_output(Level::Low);
         let led2 = port0.p0_19.into_push_pull_output(Level::Low);
 
+        // Matches what rusty_rover::battery expects
+        let saadc_config = hal::saadc::SaadcConfig {
+            resolution: hal::saadc::Resolution::_12BIT,
+            reference: hal::saadc::Reference::INTERNAL,
+            gain: hal::saadc::Gain::GAIN1_6,
+            ..Default::default()
+        };
+        let saadc = hal::saadc::Saadc::new(cx.device.SAADC, saadc_config);
+        let battery_pin = port0.p0_29.into_floating_input();
+
+        // Only reads flash, so this works before the SoftDevice is enabled
+        let (settings_store, settings) = SettingsStore::load();
+        let fault_log = FaultLog::load();
+        defmt::info!("{} fault(s) logged.", fault_log.len());
+        for fault in fault_log.iter() {
+            defmt::info!("Fault {}", fault);
+        }
+
+        // Has to be read before the SoftDevice takes over POWER
+        let reset_reason = cx.device.POWER.resetreas.read().bits();
+        cx.device
+            .POWER
+            .resetreas
+            .write(|w| unsafe { w.bits(reset_reason) });
+        let reset_fault = crash::take(reset_reason);
+        if let Some(fault) = &reset_fault {
+            defmt::warn!("Reset by fault {}", fault);
+        }
+        // Soft resets by crash::reset() were counted before resetting
+        if reset_reason & crash::RESET_WATCHDOG != 0 {
+            defmt::error!("Reset by the watchdog!");
+        }
+        if reset_reason & crash::RESET_LOCKUP != 0 {
+            defmt::error!("Reset by CPU lockup!");
+        }
+        if reset_reason & (crash::RESET_WATCHDOG | crash::RESET_LOCKUP) != 0 {
+            crash::count_fault_reset();
+        }
+        // Other resets, e.g. power-up or the reset pin, leave safe mode
+        let fault_reset = crash::RESET_SOFT | crash::RESET_WATCHDOG | crash::RESET_LOCKUP;
+        if reset_reason & fault_reset == 0 {
+            crash::clear_fault_resets();
+        }
+        let fault_resets = crash::fault_resets();
+        let safe_mode = fault_resets >= MAX_FAULT_RESETS;
+        if safe_mode {
+            defmt::error!("{} fault resets in a row, motors disabled.", fault_resets);
+        } else {
+            clear_fault_resets::spawn_after(FAULT_FREE_PERIOD_MS.millis()).unwrap();
+        }
+
+        let (wdt_sd_events, wdt_motors, wdt_timers) = match Watchdog::try_new(cx.device.WDT) {
+            Ok(mut watchdog) => {
+                watchdog.set_lfosc_ticks(WDT_TIMEOUT_MS * 32768 / 1000);
+                watchdog.run_during_sleep(true);
+                watchdog.halt_during_debug(true);
+                watchdog.enable_interrupt();
+                watchdog.activate::<count::Three>().handles
+            }
+            // Keeps running across soft resets, with its configuration
+            Err(wdt) => match Watchdog::try_recover::<count::Three>(wdt) {
+                Ok(parts) => parts.handles,
+                Err(_) => panic!("Watchdog running with other reload registers"),
+            },
+        };
+        watchdog::spawn().unwrap();
+        ramp::spawn().unwrap();
+
         defmt::info!("HW initialization finished.");
 
         /* Note we cannot initialize the SoftDevice here, as we need SVC
@@ -71,21 +217,35 @@ mod app {
         init_soft_device::spawn().unwrap();
 
         blink::spawn_after(500u32.millis()).unwrap();
+        telemetry::spawn_after(TELEMETRY_PERIOD_MS.millis()).unwrap();
+        battery_monitor::spawn().unwrap();
 
-        let mut motors_stby = port0.p0_02.into_push_pull_output(Level::Low);
-        let mut motors_r_dir = port0.p0_03.into_push_pull_output(Level::Low).degrade();
-        let mut motors_l_dir = port0.p0_04.into_push_pull_output(Level::Low).degrade();
+        let motors_stby = port0.p0_02.into_push_pull_output(Level::Low).degrade();
+        let motors_r_dir = port0.p0_03.into_push_pull_output(Level::Low).degrade();
+        let motors_l_dir = port0.p0_04.into_push_pull_output(Level::Low).degrade();
         let mut motors_r_pwm = port0.p0_05.into_push_pull_output(Level::Low).degrade();
         let mut motors_l_pwm = port0.p0_28.into_push_pull_output(Level::Low).degrade();
 
-        motors_stby.set_high().unwrap();
-        motors_r_dir.set_high().unwrap();
-        motors_l_dir.set_high().unwrap();
         motors_r_pwm.set_high().unwrap();
         motors_l_pwm.set_high().unwrap();
 
+        // Stops the motors if the SoftDevice asserts, whatever task it
+        // interrupted
+        soft_device::set_fault_policy(FaultPolicy {
+            safe_low_pins: [
+                &motors_stby,
+                &motors_r_dir,
+                &motors_l_dir,
+                &motors_r_pwm,
+                &motors_l_pwm,
+            ]
+            .iter()
+            .fold(0, |pins, pin| pins | 1 << pin.pin()),
+            ..FaultPolicy::default()
+        });
+
         let pwm = hal::pwm::Pwm::new(cx.device.PWM0);
-        pwm.set_period(2000u32.hz())
+        pwm.set_period(settings.pwm_freq_hz.hz())
             .set_output_pin(hal::pwm::Channel::C0, motors_r_pwm)
             .set_output_pin(hal::pwm::Channel::C1, motors_l_pwm);
 
@@ -95,19 +255,56 @@ mod app {
 
         pwm.enable();
 
+        let mut motors = DualMotorDriver::new(
+            pwm,
+            Motor::new(motors_l_dir, hal::pwm::Channel::C1),
+            Motor::new(motors_r_dir, hal::pwm::Channel::C0),
+            motors_stby,
+        );
+        motors.set_inverted(settings.invert_left, settings.invert_right);
+        motors.set(0, 0).unwrap();
+        motors.standby(safe_mode).unwrap();
+
+        let mut sd = SoftDevice::new(
+            |speed_r, speed_l| value_update_handler::spawn(speed_r, speed_l).unwrap(),
+            || failsafe::spawn(FailsafeReason::Disconnected).unwrap(),
+            |command| configure::spawn(command).is_ok(),
+            |name| {
+                if store_device_name::spawn(name).is_err() {
+                    defmt::error!("Device name changed again before it was stored!");
+                }
+            },
+        );
+        // Only stored here, applied in init_soft_device()
+        sd.set_device_name(&settings.device_name).unwrap();
+
         (
             Shared {
-                sd: SoftDevice::new(|speed_r, speed_l| {
-                    value_update_handler::spawn(speed_r, speed_l).unwrap()
-                }),
-                blink_freq: 0,
+                sd,
+                blink_freq: if safe_mode { ERROR_BLINK_FREQ } else { 0 },
+                motors,
+                ramps: DualRamp::new(settings.ramp_limits, settings.ramp_limits),
+                faults: if safe_mode {
+                    Faults::SAFE_MODE
+                } else {
+                    Faults::empty()
+                },
+                battery_mv: 0,
+                settings,
+                settings_store,
+                fault_log,
+                ramp_idle: None,
+                power_warnings: 0,
             },
             Local {
                 led1,
                 led2,
-                pwm,
-                motor_r_dir: motors_r_dir,
-                motor_l_dir: motors_l_dir,
+                saadc,
+                battery_pin,
+                reset_fault,
+                wdt_sd_events,
+                wdt_motors,
+                wdt_timers,
             },
             init::Monotonics(mono_clock),
         )
@@ -131,46 +328,316 @@ mod app {
     #[idle]
     fn idle(_: idle::Context) -> ! {
         loop {
-            continue;
+            soft_device::wait_for_event();
         }
     }
 
-    #[task(shared = [sd])]
+    #[task(shared = [sd, blink_freq, fault_log], local = [reset_fault, retries: u8 = 0])]
     fn init_soft_device(mut ctx: init_soft_device::Context) {
         /* Initialize SoftDevice here, as interrupts are enabled so we
          * can use SVC.
          */
-        ctx.shared.sd.lock(|sd| sd.init());
+        let result = ctx.shared.sd.lock(|sd| sd.init());
+        match result {
+            Ok(()) => {
+                (ctx.shared.sd, ctx.shared.fault_log).lock(|sd, log| {
+                    if let Err(e) = sd.set_faults(log) {
+                        defmt::error!("Failed to update faults: {}", e);
+                    }
+                });
+                if let Some(fault) = ctx.local.reset_fault.take() {
+                    record_fault::spawn(fault).ok();
+                }
+            }
+            Err(e) => {
+                defmt::error!("SoftDevice initialization failed: {}", e);
+                ctx.shared.sd.lock(|sd| sd.disable());
+                if *ctx.local.retries < SD_INIT_RETRIES {
+                    *ctx.local.retries += 1;
+                    init_soft_device::spawn_after(SD_INIT_RETRY_DELAY_MS.millis()).unwrap();
+                } else {
+                    defmt::error!("Giving up on SoftDevice initialization.");
+                    ctx.shared.blink_freq.lock(|freq| *freq = ERROR_BLINK_FREQ);
+                }
+            }
+        }
     }
 
-    #[task(local = [pwm, motor_r_dir, motor_l_dir])]
-    fn value_update_handler(ctx: value_update_handler::Context, speed_r: i8, speed_l: i8) {
+    #[task(
+        shared = [motors, ramps, faults, settings, ramp_idle],
+        local = [failsafe_handle: Option<failsafe::SpawnHandle> = None],
+        priority = 3
+    )]
+    fn value_update_handler(mut ctx: value_update_handler::Context, speed_r: i8, speed_l: i8) {
         /* This task is spawned "deep" within SoftDevice::handle_evt_notify()
          * as that it what we handed to SoftDevice::new() in app::init()
          * above.
          */
         defmt::info!("New speed value received via BLE: {} {}", speed_r, speed_l);
-        let max_duty: u32 = ctx.local.pwm.max_duty().try_into().unwrap();
-        let (ch0, ch1, _, _) = ctx.local.pwm.split_channels();
-        if speed_r > 0 {
-            ctx.local.motor_r_dir.set_high().unwrap();
-        } else {
-            ctx.local.motor_r_dir.set_low().unwrap();
+        let faults = ctx.shared.faults.lock(|faults| *faults);
+        if faults.contains(Faults::SAFE_MODE) {
+            defmt::warn!("Ignoring speed value in safe mode.");
+            return;
         }
-        if speed_l > 0 {
-            ctx.local.motor_l_dir.set_high().unwrap();
+        if faults.contains(Faults::POWER_FAILURE) {
+            defmt::warn!("Ignoring speed value until the power failure is over.");
+            return;
+        }
+
+        let (timeout_ms, max_speed) = ctx
+            .shared
+            .settings
+            .lock(|settings| (settings.failsafe_timeout_ms, settings.max_speed as i8));
+        let speed_l = speed_l.clamp(-max_speed, max_speed);
+        let speed_r = speed_r.clamp(-max_speed, max_speed);
+
+        // (Re-)arm the failsafe, it fires unless the next value arrives in time
+        let timeout = timeout_ms.millis();
+        *ctx.local.failsafe_handle = ctx
+            .local
+            .failsafe_handle
+            .take()
+            .and_then(|handle| handle.reschedule_after(timeout).ok())
+            .or_else(|| failsafe::spawn_after(timeout, FailsafeReason::Timeout).ok());
+
+        ctx.shared
+            .ramps
+            .lock(|ramps| ramps.set_target(speed_l, speed_r));
+        ctx.shared
+            .faults
+            .lock(|faults| faults.remove(Faults::FAILSAFE));
+        // Leave standby in case the failsafe kicked in before
+        ctx.shared
+            .motors
+            .lock(|motors| motors.standby(false).unwrap());
+        // A settled ramp only steps once in a while, start it right away
+        if let Some(handle) = ctx.shared.ramp_idle.lock(|ramp_idle| ramp_idle.take()) {
+            handle.cancel().ok();
+        }
+        // Fails if the ramp is already running, which is just fine
+        ramp::spawn().ok();
+    }
+
+    #[task(local = [wdt_timers])]
+    fn watchdog(ctx: watchdog::Context) {
+        /* This task checks in for the timer queue, which e.g. the failsafe
+         * relies on. ramp() checks in itself, softdev_event_notify() after
+         * draining the SoftDevice events. Their interrupt is triggered here
+         * in case the SoftDevice has nothing to report, so the check-in
+         * still goes all the way through the interrupt and event queues.
+         */
+        ctx.local.wdt_timers.pet();
+        rtic::pend(hal::pac::Interrupt::SWI2_EGU2);
+        watchdog::spawn_after(WATCHDOG_PERIOD_MS.millis()).unwrap();
+    }
+
+    #[task(binds = WDT, priority = 6)]
+    fn watchdog_timeout(_: watchdog_timeout::Context) {
+        // The chip resets two 32 kHz cycles later, the reset reason tells
+        // if this didn't make it
+        let pending = unsafe { (*hal::pac::WDT::ptr()).reqstatus.read().bits() };
+        crash::record(&Fault::with_data(FaultKind::Watchdog, &[pending]));
+    }
+
+    #[task]
+    fn clear_fault_resets(_: clear_fault_resets::Context) {
+        crash::clear_fault_resets();
+    }
+
+    #[task(shared = [motors, ramps, ramp_idle], local = [wdt_motors], priority = 3)]
+    fn ramp(mut ctx: ramp::Context) {
+        /* Runs all the time, slowly once settled, see
+         * value_update_handler(). Checking in here shows the motor outputs
+         * are still updated.
+         */
+        ctx.local.wdt_motors.pet();
+        let settled = (ctx.shared.motors, ctx.shared.ramps).lock(|motors, ramps| {
+            let (speed_l, speed_r) = ramps.step();
+            motors.set(speed_l, speed_r).unwrap();
+            ramps.is_settled()
+        });
+        if settled {
+            let handle = ramp::spawn_after(RAMP_IDLE_PERIOD_MS.millis()).unwrap();
+            ctx.shared
+                .ramp_idle
+                .lock(|ramp_idle| *ramp_idle = Some(handle));
         } else {
-            ctx.local.motor_l_dir.set_low().unwrap();
+            ramp::spawn_after(RAMP_PERIOD_MS.millis()).unwrap();
+        }
+    }
+
+    #[task(shared = [motors, ramps, faults], capacity = 2, priority = 3)]
+    fn failsafe(ctx: failsafe::Context, reason: FailsafeReason) {
+        /* Spawned by the SoftDevice on disconnect and scheduled by
+         * value_update_handler() for when the central stops sending speed
+         * values.
+         */
+        defmt::warn!("Failsafe triggered ({}), stopping motors.", reason);
+        (ctx.shared.motors, ctx.shared.ramps, ctx.shared.faults).lock(|motors, ramps, faults| {
+            // No ramping down here, this has to be immediate
+            ramps.reset();
+            motors.coast().unwrap();
+            faults.insert(Faults::FAILSAFE);
+        });
+    }
+
+    #[task(shared = [sd, settings, settings_store, motors, ramps], capacity = 2)]
+    fn configure(mut ctx: configure::Context, command: Command) {
+        /* Spawned by the SoftDevice for commands written to the
+         * configuration characteristic, see rusty_rover::config.
+         */
+        defmt::info!("Config command: {}", command.opcode());
+        let opcode = command.opcode() as u8;
+        // Flash operations wait for the SoftDevice event interrupt, so the
+        // settings (shared with value_update_handler() above it) are copied
+        // instead of locked while storing them. Only changes are applied,
+        // as that reprograms the PWM.
+        let (reply, changed) = match command {
+            Command::Get(key) => {
+                let mut value = [0; MAX_VALUE_LEN];
+                let len = ctx
+                    .shared
+                    .settings
+                    .lock(|settings| settings.get(key, &mut value));
+                (Reply::with_value(&value[..len]), None)
+            }
+            Command::Set(key, value) => {
+                let result = ctx
+                    .shared
+                    .settings
+                    .lock(|settings| settings.set(key, &value).map(|()| settings.clone()));
+                match result {
+                    Ok(settings) => (Reply::new(opcode, Status::Success), Some(settings)),
+                    Err(e) => (Reply::new(opcode, e.into()), None),
+                }
+            }
+            Command::Commit => {
+                let settings = ctx.shared.settings.lock(|settings| settings.clone());
+                match ctx
+                    .shared
+                    .settings_store
+                    .lock(|store| store.save(&settings))
+                {
+                    Ok(()) => (Reply::new(opcode, Status::Success), None),
+                    Err(e) => {
+                        defmt::error!("Failed to store settings: {}", e);
+                        (Reply::new(opcode, Status::StorageFailed), None)
+                    }
+                }
+            }
+            Command::ResetDefaults => {
+                ctx.shared
+                    .settings
+                    .lock(|settings| *settings = Settings::default());
+                let reply = match ctx.shared.settings_store.lock(|store| store.reset()) {
+                    Ok(()) => Reply::new(opcode, Status::Success),
+                    Err(e) => {
+                        defmt::error!("Failed to reset settings: {}", e);
+                        Reply::new(opcode, Status::StorageFailed)
+                    }
+                };
+                (reply, Some(Settings::default()))
+            }
+        };
+        if let Some(settings) = &changed {
+            (ctx.shared.motors, ctx.shared.ramps)
+                .lock(|motors, ramps| apply_settings(settings, motors, ramps));
         }
+        ctx.shared.sd.lock(|sd| {
+            if let Some(settings) = &changed {
+                if let Err(e) = sd.set_device_name(&settings.device_name) {
+                    defmt::error!("Failed to set device name: {}", e);
+                }
+            }
+            if let Err(e) = sd.send_config_reply(&reply) {
+                defmt::error!("Failed to send config reply: {}", e);
+            }
+        });
+    }
 
-        let speed_r_abs: u32 = speed_r.abs().try_into().unwrap();
-        let speed_l_abs: u32 = speed_l.abs().try_into().unwrap();
+    #[task(shared = [settings, settings_store])]
+    fn store_device_name(mut ctx: store_device_name::Context, name: String<MAX_NAME_LEN>) {
+        /* Spawned by the SoftDevice after the central wrote the GAP device
+         * name characteristic. Like configure(), this stores a copy of the
+         * settings.
+         */
+        let settings = ctx.shared.settings.lock(|settings| {
+            settings.device_name = name;
+            settings.clone()
+        });
+        // Settings changed but not committed by the central stay unsaved
+        let result = ctx
+            .shared
+            .settings_store
+            .lock(|store| store.save_key(Key::DeviceName, &settings));
+        if let Err(e) = result {
+            defmt::error!("Failed to store device name: {}", e);
+        }
+    }
 
-        let duty0: u16 = (max_duty * speed_r_abs / 128).try_into().unwrap();
-        let duty1: u16 = (max_duty * speed_l_abs / 128).try_into().unwrap();
+    /// Applies the settings which take effect right away. The failsafe
+    /// timeout and maximum speed are read for each speed value.
+    fn apply_settings(settings: &Settings, motors: &mut Motors, ramps: &mut DualRamp) {
+        ramps.set_limits(settings.ramp_limits, settings.ramp_limits);
+        motors.set_inverted(settings.invert_left, settings.invert_right);
+        motors.pwm().set_period(settings.pwm_freq_hz.hz());
+        // The duty cycle depends on the period, set the speeds again
+        motors
+            .set(ramps.left.current(), ramps.right.current())
+            .unwrap();
+    }
+
+    #[task(shared = [sd, motors, faults, battery_mv])]
+    fn telemetry(mut ctx: telemetry::Context) {
+        telemetry::spawn_after(TELEMETRY_PERIOD_MS.millis()).unwrap();
 
-        ch0.set_duty_off(duty0);
-        ch1.set_duty_off(duty1);
+        let mut telemetry = Telemetry {
+            uptime_s: monotonics::now().duration_since_epoch().to_secs() as u32,
+            ..Default::default()
+        };
+        telemetry.battery_mv = ctx.shared.battery_mv.lock(|battery_mv| *battery_mv);
+        (ctx.shared.sd, ctx.shared.motors, ctx.shared.faults).lock(|sd, motors, faults| {
+            let (duty_left, duty_right) = motors.duty();
+            let (reverse_left, reverse_right) = motors.reverse();
+            telemetry.duty_left = duty_left;
+            telemetry.duty_right = duty_right;
+            telemetry.reverse_left = reverse_left;
+            telemetry.reverse_right = reverse_right;
+            telemetry.standby = motors.is_standby();
+            telemetry.faults = *faults;
+            if let Err(e) = sd.notify_telemetry(&telemetry) {
+                defmt::error!("Failed to send telemetry: {}", e);
+            }
+        });
+    }
+
+    #[task(shared = [sd, faults, battery_mv], local = [saadc, battery_pin])]
+    fn battery_monitor(mut ctx: battery_monitor::Context) {
+        battery_monitor::spawn_after(BATTERY_PERIOD_MS.millis()).unwrap();
+
+        let sample = match ctx.local.saadc.read(ctx.local.battery_pin) {
+            Ok(sample) => sample,
+            Err(_) => {
+                defmt::error!("Failed to sample battery voltage!");
+                return;
+            }
+        };
+        let battery_mv = BATTERY.battery_mv(sample);
+        let percent = BATTERY.percent(battery_mv);
+        defmt::debug!("Battery: {} mV, {}%", battery_mv, percent);
+
+        ctx.shared.battery_mv.lock(|mv| *mv = battery_mv);
+        ctx.shared.faults.lock(|faults| {
+            if percent < LOW_BATTERY_PERCENT {
+                faults.insert(Faults::LOW_BATTERY);
+            } else {
+                faults.remove(Faults::LOW_BATTERY);
+            }
+        });
+        let result = ctx.shared.sd.lock(|sd| sd.set_battery_level(percent));
+        if let Err(e) = result {
+            defmt::error!("Failed to update battery level: {}", e);
+        }
     }
 
     /* We need two tasks for handling SoftDevice events:
@@ -183,13 +650,114 @@ mod app {
      * problem is hard to understand (SoftDevice assert with and address
      * and nothing more).
      */
-    #[task(shared = [sd])]
+    #[task(shared = [sd], local = [wdt_sd_events])]
     fn softdev_event_notify(mut ctx: softdev_event_notify::Context) {
-        ctx.shared.sd.lock(|sd| sd.handle_evt_notify());
+        // Only spawned by the SoftDevice event interrupt below
+        if ctx.shared.sd.lock(|sd| sd.handle_evt_notify()) {
+            ctx.local.wdt_sd_events.pet();
+        }
     }
 
-    #[task(binds = SWI2_EGU2)]
+    #[task(capacity = 4)]
+    fn soc_event(_ctx: soc_event::Context, event: SocEvent) {
+        // Flash events were already handled by handle_soc_events() and
+        // power failures by power_failure()
+        match event {
+            SocEvent::FlashOperationSuccess
+            | SocEvent::FlashOperationError
+            | SocEvent::PowerFailureWarning => (),
+            _ => defmt::debug!("SoC event: {}", event),
+        }
+    }
+
+    #[task(
+        shared = [motors, ramps, faults, blink_freq, power_warnings],
+        local = [rearm_handle: Option<power_failure_rearm::SpawnHandle> = None],
+        priority = 2
+    )]
+    fn power_failure(mut ctx: power_failure::Context) {
+        /* Spawned right from the SoftDevice's event interrupt, so the
+         * motors stop before they drag the supply below the brown-out
+         * level. Speed values are ignored until power_failure_rearm()
+         * clears POWER_FAILURE, each warning restarts the hold-off.
+         */
+        (ctx.shared.motors, ctx.shared.ramps, ctx.shared.faults).lock(|motors, ramps, faults| {
+            ramps.reset();
+            motors.coast().unwrap();
+            motors.standby(true).unwrap();
+            faults.insert(Faults::POWER_FAILURE);
+        });
+        ctx.shared
+            .blink_freq
+            .lock(|freq| *freq = (*freq).max(POWER_FAILURE_BLINK_FREQ));
+        let hold = POWER_FAILURE_HOLD_MS.millis();
+        *ctx.local.rearm_handle = ctx
+            .local
+            .rearm_handle
+            .take()
+            .and_then(|handle| handle.reschedule_after(hold).ok())
+            .or_else(|| power_failure_rearm::spawn_after(hold).ok());
+        // Flash can't be written with VDD below the power failure threshold,
+        // so the warnings are logged once the supply recovered
+        ctx.shared
+            .power_warnings
+            .lock(|warnings| *warnings = warnings.saturating_add(1));
+    }
+
+    #[task(shared = [faults, battery_mv, blink_freq, power_warnings])]
+    fn power_failure_rearm(mut ctx: power_failure_rearm::Context) {
+        /* The motors may only run again once the battery recovered, which
+         * also keeps them off while the battery isn't measured at all. All
+         * warnings until then are logged as one fault.
+         */
+        let battery_mv = ctx.shared.battery_mv.lock(|battery_mv| *battery_mv);
+        if BATTERY.percent(battery_mv) < LOW_BATTERY_PERCENT {
+            defmt::warn!("Battery at {} mV, motors stay off.", battery_mv);
+            power_failure_rearm::spawn_after(POWER_FAILURE_HOLD_MS.millis()).ok();
+            return;
+        }
+        defmt::info!("Power failure over, motors may run again.");
+        let warnings = ctx
+            .shared
+            .power_warnings
+            .lock(|warnings| core::mem::take(warnings));
+        record_fault::spawn(Fault::with_data(FaultKind::PowerFailure, &[warnings])).ok();
+        ctx.shared
+            .faults
+            .lock(|faults| faults.remove(Faults::POWER_FAILURE));
+        ctx.shared.blink_freq.lock(|freq| {
+            if *freq == POWER_FAILURE_BLINK_FREQ {
+                *freq = 0;
+            }
+        });
+    }
+
+    #[task(shared = [sd, fault_log], capacity = 2)]
+    fn record_fault(ctx: record_fault::Context, fault: Fault) {
+        (ctx.shared.sd, ctx.shared.fault_log).lock(|sd, log| match log.record(fault) {
+            Ok(fault) => {
+                defmt::warn!("Fault {}", fault);
+                if let Err(e) = sd.set_faults(log) {
+                    defmt::error!("Failed to update faults: {}", e);
+                }
+            }
+            Err(e) => defmt::error!("Failed to log fault: {}", e),
+        });
+    }
+
+    /* Runs above the other tasks, so flash operations waiting for their SoC
+     * event in a task (see rusty_rover::flash) can complete.
+     */
+    #[task(binds = SWI2_EGU2, priority = 2)]
     fn softdev_event_notify_interrupt(_ctx: softdev_event_notify_interrupt::Context) {
-        softdev_event_notify::spawn().unwrap();
+        // Dropped if the queue is full, flash events are handled anyway
+        soft_device::handle_soc_events(|event| {
+            if event == SocEvent::PowerFailureWarning {
+                power_failure::spawn().ok();
+            }
+            soc_event::spawn(event).ok();
+        });
+        // Fails if already pending, which drains all BLE events anyway
+        softdev_event_notify::spawn().ok();
     }
 }
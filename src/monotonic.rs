@@ -0,0 +1,127 @@
+//! RTIC monotonic timer on RTC1, which keeps running while the CPU sleeps.
+//! RTC0 belongs to the SoftDevice.
+//!
+//! The RTC counts the 32.768 kHz LFCLK in 24 bits, overflows are counted
+//! in software to extend it to 64 bits, so instants never wrap.
+
+use nrf52832_hal::pac::{Interrupt, NVIC, RTC1};
+use rtic::Monotonic;
+
+pub const TICK_HZ: u32 = 32_768;
+
+pub type Instant = fugit::TimerInstantU64<TICK_HZ>;
+pub type Duration = fugit::TimerDurationU64<TICK_HZ>;
+
+const COUNTER_BITS: u32 = 24;
+const COUNTER_MASK: u32 = (1 << COUNTER_BITS) - 1;
+
+/// Like `fugit::ExtU32`, but for the monotonic's 64 bit durations, so
+/// `u32` constants can be used with `spawn_after()`.
+pub trait ExtU32 {
+    fn millis(self) -> Duration;
+    fn secs(self) -> Duration;
+}
+
+impl ExtU32 for u32 {
+    fn millis(self) -> Duration {
+        Duration::millis(self as u64)
+    }
+
+    fn secs(self) -> Duration {
+        Duration::secs(self as u64)
+    }
+}
+
+/// Combines the software overflow count with the RTC state into ticks.
+/// `overflow_pending` is the overflow event not counted yet, which has to
+/// be read before `counter`: set then, the counter has already wrapped.
+pub fn ticks(overflows: u64, overflow_pending: bool, counter: u32) -> u64 {
+    (overflows + overflow_pending as u64) << COUNTER_BITS | (counter & COUNTER_MASK) as u64
+}
+
+pub struct Rtc1Monotonic {
+    rtc: RTC1,
+    overflows: u64,
+}
+
+impl Rtc1Monotonic {
+    /// Starts the RTC. The LFCLK has to be running, or be started before
+    /// the first timer is due.
+    pub fn new(rtc: RTC1) -> Rtc1Monotonic {
+        rtc.tasks_stop.write(|w| unsafe { w.bits(1) });
+        rtc.tasks_clear.write(|w| unsafe { w.bits(1) });
+        rtc.prescaler.write(|w| unsafe { w.bits(0) });
+        // Overflows have to be counted even without a timer pending
+        rtc.intenset.write(|w| w.ovrflw().set());
+        rtc.tasks_start.write(|w| unsafe { w.bits(1) });
+        Rtc1Monotonic { rtc, overflows: 0 }
+    }
+
+    fn overflow_pending(&self) -> bool {
+        self.rtc.events_ovrflw.read().bits() != 0
+    }
+}
+
+impl Monotonic for Rtc1Monotonic {
+    // The overflow interrupt has to stay enabled
+    const DISABLE_INTERRUPT_ON_EMPTY_QUEUE: bool = false;
+
+    type Instant = Instant;
+    type Duration = Duration;
+
+    fn now(&mut self) -> Instant {
+        // An overflow between reading the counter and the event would be
+        // counted for the wrong value
+        loop {
+            let before = self.overflow_pending();
+            let counter = self.rtc.counter.read().bits();
+            if self.overflow_pending() == before {
+                return Instant::from_ticks(ticks(self.overflows, before, counter));
+            }
+        }
+    }
+
+    fn set_compare(&mut self, instant: Instant) {
+        let now = self.now();
+        // The RTC misses compare values of COUNTER and COUNTER + 1. Timers
+        // that close, or in the past, are handled right away by pending the
+        // interrupt. Ones after the next overflow may fire early, RTIC sets
+        // the compare again then.
+        match instant.checked_duration_since(now) {
+            Some(duration) if duration.ticks() > 2 => {
+                let compare = instant.ticks() as u32 & COUNTER_MASK;
+                self.rtc.cc[0].write(|w| unsafe { w.bits(compare) });
+            }
+            _ => NVIC::pend(Interrupt::RTC1),
+        }
+    }
+
+    fn clear_compare_flag(&mut self) {
+        self.rtc.events_compare[0].write(|w| unsafe { w.bits(0) });
+    }
+
+    fn zero() -> Instant {
+        Instant::from_ticks(0)
+    }
+
+    unsafe fn reset(&mut self) {
+        self.rtc.tasks_clear.write(|w| w.bits(1));
+        self.rtc.events_ovrflw.write(|w| w.bits(0));
+        self.overflows = 0;
+    }
+
+    fn on_interrupt(&mut self) {
+        if self.overflow_pending() {
+            self.rtc.events_ovrflw.write(|w| unsafe { w.bits(0) });
+            self.overflows += 1;
+        }
+    }
+
+    fn enable_timer(&mut self) {
+        self.rtc.intenset.write(|w| w.compare0().set());
+    }
+
+    fn disable_timer(&mut self) {
+        self.rtc.intenclr.write(|w| w.compare0().clear());
+    }
+}
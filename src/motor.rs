@@ -0,0 +1,173 @@
+//! Driver for the TB6612FNG dual H-bridge driving the rover's two motors.
+//!
+//! Each side is controlled by one direction pin and one PWM channel, both
+//! sides share the standby pin. The driver is generic over the pins and the
+//! PWM peripheral, so it can be tested with mock implementations.
+
+use embedded_hal::digital::v2::OutputPin;
+use nrf52832_hal::pwm;
+
+/// PWM peripheral with (at least) one channel per motor.
+pub trait MotorPwm {
+    type Channel: Copy;
+
+    /// Duty value corresponding to full speed
+    fn max_duty(&self) -> u16;
+    fn set_duty(&mut self, channel: Self::Channel, duty: u16);
+}
+
+impl<T: pwm::Instance> MotorPwm for pwm::Pwm<T> {
+    type Channel = pwm::Channel;
+
+    fn max_duty(&self) -> u16 {
+        pwm::Pwm::max_duty(self)
+    }
+
+    fn set_duty(&mut self, channel: pwm::Channel, duty: u16) {
+        self.set_duty_off(channel, duty);
+    }
+}
+
+/// One side of the rover: direction pin, PWM channel and mounting direction.
+pub struct Motor<DIR, CH> {
+    dir: DIR,
+    channel: CH,
+    inverted: bool,
+    // Last applied values, for reporting
+    duty: u16,
+    reverse: bool,
+}
+
+impl<DIR, CH> Motor<DIR, CH> {
+    pub fn new(dir: DIR, channel: CH) -> Self {
+        Motor {
+            dir,
+            channel,
+            inverted: false,
+            duty: 0,
+            reverse: false,
+        }
+    }
+}
+
+pub struct DualMotorDriver<PWM: MotorPwm, DIR, STBY> {
+    pwm: PWM,
+    left: Motor<DIR, PWM::Channel>,
+    right: Motor<DIR, PWM::Channel>,
+    stby: STBY,
+    standby: bool,
+}
+
+impl<PWM, DIR, STBY, E> DualMotorDriver<PWM, DIR, STBY>
+where
+    PWM: MotorPwm,
+    DIR: OutputPin<Error = E>,
+    STBY: OutputPin<Error = E>,
+{
+    pub fn new(
+        pwm: PWM,
+        left: Motor<DIR, PWM::Channel>,
+        right: Motor<DIR, PWM::Channel>,
+        stby: STBY,
+    ) -> Self {
+        DualMotorDriver {
+            pwm,
+            left,
+            right,
+            stby,
+            standby: false,
+        }
+    }
+
+    /// Inverts the direction of either side, e.g. for a motor mounted
+    /// the other way round or with swapped leads.
+    pub fn set_inverted(&mut self, left: bool, right: bool) {
+        self.left.inverted = left;
+        self.right.inverted = right;
+    }
+
+    /// Sets the speed of both sides. Positive values drive forward,
+    /// -128 and 127 correspond to (almost) full speed.
+    pub fn set(&mut self, left: i8, right: i8) -> Result<(), E> {
+        let max_duty = self.pwm.max_duty();
+        Self::set_side(&mut self.pwm, &mut self.left, max_duty, left)?;
+        Self::set_side(&mut self.pwm, &mut self.right, max_duty, right)
+    }
+
+    /// Short-brakes both motors. With PWM low, the TB6612FNG shorts the
+    /// motor terminals regardless of the direction inputs.
+    pub fn brake(&mut self) -> Result<(), E> {
+        self.stop();
+        self.standby(false)
+    }
+
+    /// Lets both motors spin freely. The outputs can only be switched to
+    /// high impedance via the shared standby pin, so this affects both sides.
+    pub fn coast(&mut self) -> Result<(), E> {
+        self.stop();
+        self.standby(true)
+    }
+
+    /// In standby, the H-bridge outputs are high impedance and the motors
+    /// don't draw any current.
+    pub fn standby(&mut self, standby: bool) -> Result<(), E> {
+        // STBY is active low
+        if standby {
+            self.stby.set_low()?;
+        } else {
+            self.stby.set_high()?;
+        }
+        self.standby = standby;
+        Ok(())
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby
+    }
+
+    /// Currently applied duty as `(left, right)`, `0..=max_duty`
+    pub fn duty(&self) -> (u16, u16) {
+        (self.left.duty, self.right.duty)
+    }
+
+    /// Whether the sides currently drive backwards, as `(left, right)`
+    pub fn reverse(&self) -> (bool, bool) {
+        (self.left.reverse, self.right.reverse)
+    }
+
+    /// Gives access to the PWM peripheral, e.g. for changing its period.
+    pub fn pwm(&mut self) -> &mut PWM {
+        &mut self.pwm
+    }
+
+    /// Returns the PWM peripheral, the left and right direction pins and
+    /// the standby pin.
+    pub fn release(self) -> (PWM, DIR, DIR, STBY) {
+        (self.pwm, self.left.dir, self.right.dir, self.stby)
+    }
+
+    fn stop(&mut self) {
+        for motor in [&mut self.left, &mut self.right] {
+            self.pwm.set_duty(motor.channel, 0);
+            motor.duty = 0;
+        }
+    }
+
+    fn set_side(
+        pwm: &mut PWM,
+        motor: &mut Motor<DIR, PWM::Channel>,
+        max_duty: u16,
+        speed: i8,
+    ) -> Result<(), E> {
+        if (speed > 0) != motor.inverted {
+            motor.dir.set_high()?;
+        } else {
+            motor.dir.set_low()?;
+        }
+        let duty = (max_duty as u32 * speed.unsigned_abs() as u32 / 128) as u16;
+        pwm.set_duty(motor.channel, duty);
+        motor.duty = duty;
+        motor.reverse = speed < 0;
+        Ok(())
+    }
+}
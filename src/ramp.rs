@@ -0,0 +1,133 @@
+//! Slew rate limiting for the motor speeds.
+//!
+//! Sudden speed changes, especially full forward to full reverse, make the
+//! battery voltage collapse and are hard on the gears. A `Ramp` moves the
+//! applied speed towards the commanded one in small steps instead. Stepping
+//! is driven by a periodic task, so the limits are given per step.
+
+/// Maximum speed change per step, in the units of `DualMotorDriver::set()`.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RampLimits {
+    /// Speeding up in the current direction
+    pub accel: u8,
+    /// Slowing down in the current direction
+    pub decel: u8,
+    /// Slowing down towards standstill when the target is in the opposite
+    /// direction. Once stopped, `accel` applies.
+    pub reversal: u8,
+}
+
+pub struct Ramp {
+    current: i8,
+    target: i8,
+    limits: RampLimits,
+}
+
+impl Ramp {
+    pub const fn new(limits: RampLimits) -> Ramp {
+        Ramp {
+            current: 0,
+            target: 0,
+            limits,
+        }
+    }
+
+    pub fn set_limits(&mut self, limits: RampLimits) {
+        self.limits = limits;
+    }
+
+    pub fn set_target(&mut self, target: i8) {
+        self.target = target;
+    }
+
+    pub fn target(&self) -> i8 {
+        self.target
+    }
+
+    /// Speed which should currently be applied to the motor
+    pub fn current(&self) -> i8 {
+        self.current
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.current == self.target
+    }
+
+    /// Stops immediately, bypassing the limits.
+    pub fn reset(&mut self) {
+        self.current = 0;
+        self.target = 0;
+    }
+
+    /// Moves the current speed one step towards the target and returns it.
+    pub fn step(&mut self) -> i8 {
+        let current = self.current as i16;
+        let target = self.target as i16;
+
+        let next = if current != 0 && (target == 0 || (target > 0) != (current > 0)) {
+            // Slowing down to standstill, maybe to reverse afterwards
+            let limit = if target == 0 {
+                self.limits.decel
+            } else {
+                self.limits.reversal
+            };
+            Self::approach(current, 0, limit)
+        } else if target.abs() > current.abs() {
+            Self::approach(current, target, self.limits.accel)
+        } else {
+            Self::approach(current, target, self.limits.decel)
+        };
+
+        self.current = next as i8;
+        self.current
+    }
+
+    fn approach(from: i16, to: i16, limit: u8) -> i16 {
+        // A limit of 0 would never get anywhere
+        let limit = limit.max(1) as i16;
+        if to > from {
+            (from + limit).min(to)
+        } else {
+            (from - limit).max(to)
+        }
+    }
+}
+
+/// Ramps for both sides of the rover, each with its own limits.
+pub struct DualRamp {
+    pub left: Ramp,
+    pub right: Ramp,
+}
+
+impl DualRamp {
+    pub const fn new(left: RampLimits, right: RampLimits) -> DualRamp {
+        DualRamp {
+            left: Ramp::new(left),
+            right: Ramp::new(right),
+        }
+    }
+
+    pub fn set_limits(&mut self, left: RampLimits, right: RampLimits) {
+        self.left.set_limits(left);
+        self.right.set_limits(right);
+    }
+
+    pub fn set_target(&mut self, left: i8, right: i8) {
+        self.left.set_target(left);
+        self.right.set_target(right);
+    }
+
+    /// Steps both sides, returns the speeds to apply as `(left, right)`.
+    pub fn step(&mut self) -> (i8, i8) {
+        (self.left.step(), self.right.step())
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.left.is_settled() && self.right.is_settled()
+    }
+
+    pub fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+    }
+}
@@ -0,0 +1,365 @@
+//! Pairing and bonding.
+//!
+//! The rover has no input and only LEDs as output, so it pairs as display
+//! only device with a static passkey set at build time. With LE Secure
+//! Connections, the Diffie-Hellman key is computed in software. Bonds are
+//! kept in two flash pages of their own, used in turns, see `BONDS` in
+//! memory.x and `bond`.
+
+use crate::bond::{self, Bond, BondStore, MAX_BONDS};
+use crate::connection::PeerAddr;
+use crate::flash::{self, PAGE_HEADER_LEN, PAGE_SIZE};
+use crate::soft_device::SdError;
+use nrf_softdevice_s112 as sd;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+
+// Set ROVER_PASSKEY (6 digits) when building. There's no default, as a
+// passkey shared by every rover wouldn't keep anyone from driving it.
+pub const PASSKEY: &str = env!("ROVER_PASSKEY");
+const _: () = assert!(is_passkey(PASSKEY), "ROVER_PASSKEY must have 6 digits");
+
+const fn is_passkey(passkey: &str) -> bool {
+    let bytes = passkey.as_bytes();
+    if bytes.len() != 6 {
+        return false;
+    }
+    let mut i = 0;
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_digit() {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+extern "C" {
+    // Defined in memory.x
+    static __bonds_start: u32;
+}
+
+const PAGES: usize = 2;
+
+fn page_addr(page: usize) -> u32 {
+    unsafe { &__bonds_start as *const u32 as u32 + page as u32 * PAGE_SIZE }
+}
+
+fn page_bytes(page: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(page_addr(page) as *const u8, PAGE_SIZE as usize) }
+}
+
+pub struct Security {
+    bonds: BondStore,
+    // Page holding the bonds and its sequence number
+    page: usize,
+    seq: u32,
+    // Device identities of the bonds, the SoftDevice resolves private
+    // addresses of bonded centrals with them
+    id_keys: [sd::ble_gap_id_key_t; MAX_BONDS],
+    identities_outdated: bool,
+    secret_key: Option<SecretKey>,
+    // Buffers the SoftDevice fills during pairing, passed via keyset
+    keyset: sd::ble_gap_sec_keyset_t,
+    own_enc_key: sd::ble_gap_enc_key_t,
+    own_pk: sd::ble_gap_lesc_p256_pk_t,
+    peer_id_key: sd::ble_gap_id_key_t,
+    peer_pk: sd::ble_gap_lesc_p256_pk_t,
+}
+
+impl Security {
+    pub fn new() -> Security {
+        // Plain C structs, all zeros is a valid (empty) value
+        unsafe {
+            Security {
+                bonds: BondStore::new(),
+                // Saving moves on to page 0
+                page: PAGES - 1,
+                seq: 0,
+                id_keys: core::mem::zeroed(),
+                identities_outdated: true,
+                secret_key: None,
+                keyset: core::mem::zeroed(),
+                own_enc_key: core::mem::zeroed(),
+                own_pk: core::mem::zeroed(),
+                peer_id_key: core::mem::zeroed(),
+                peer_pk: core::mem::zeroed(),
+            }
+        }
+    }
+
+    /// Sets the passkey, generates the LESC key pair and loads the bonds.
+    /// Has to be called after `sd_ble_enable()` and before advertising.
+    pub fn init(&mut self) -> Result<(), SdError> {
+        let mut opt: sd::ble_opt_t = unsafe { core::mem::zeroed() };
+        unsafe {
+            opt.gap_opt.as_mut().passkey.as_mut().p_passkey = PASSKEY.as_ptr();
+        }
+        SdError::check(unsafe { sd::sd_ble_opt_set(sd::BLE_GAP_OPTS_BLE_GAP_OPT_PASSKEY, &opt) })?;
+
+        let secret_key = loop {
+            let mut bytes = [0u8; 32];
+            random_bytes(&mut bytes)?;
+            // Fails for the unlikely values outside of the curve order
+            if let Ok(key) = SecretKey::from_be_bytes(&bytes) {
+                break key;
+            }
+        };
+        let point = secret_key.public_key().to_encoded_point(false);
+        // The SoftDevice expects X and Y in little endian
+        if let (Some(x), Some(y)) = (point.x(), point.y()) {
+            self.own_pk.pk[..32].copy_from_slice(x);
+            self.own_pk.pk[..32].reverse();
+            self.own_pk.pk[32..].copy_from_slice(y);
+            self.own_pk.pk[32..].reverse();
+        }
+        self.secret_key = Some(secret_key);
+
+        let current = (0..PAGES)
+            .filter_map(|page| {
+                flash::page_seq(page_bytes(page), bond::PAGE_MAGIC).map(|seq| (page, seq))
+            })
+            .max_by_key(|(_, seq)| *seq);
+        if let Some((page, seq)) = current {
+            self.bonds = BondStore::decode(&page_bytes(page)[PAGE_HEADER_LEN..]);
+            self.page = page;
+            self.seq = seq;
+        }
+        defmt::info!("{} bond(s) loaded.", self.bonds.len());
+        self.identities_outdated = true;
+        self.update_identities()
+    }
+
+    /// Hands the identities of the bonded centrals to the SoftDevice. This
+    /// fails while connected, call again after disconnecting.
+    pub fn update_identities(&mut self) -> Result<(), SdError> {
+        if !self.identities_outdated {
+            return Ok(());
+        }
+        let mut id_key_ptrs = [core::ptr::null(); MAX_BONDS];
+        for (i, bond) in self.bonds.iter().enumerate() {
+            let id_key = &mut self.id_keys[i];
+            id_key.id_info.irk = bond.irk;
+            id_key.id_addr_info.addr = bond.addr;
+            id_key.id_addr_info.set_addr_type(bond.addr_type);
+            id_key_ptrs[i] = id_key as *const sd::ble_gap_id_key_t;
+        }
+        SdError::check(unsafe {
+            sd::sd_ble_gap_device_identities_set(
+                id_key_ptrs.as_ptr(),
+                core::ptr::null(),
+                self.bonds.len() as u8,
+            )
+        })?;
+        self.identities_outdated = false;
+        Ok(())
+    }
+
+    /// Accepts pairing as display only device with MITM protection, LESC
+    /// and bonding.
+    pub fn on_sec_params_request(&mut self, conn_handle: u16) -> Result<(), SdError> {
+        let sec_params = sd::ble_gap_sec_params_t {
+            _bitfield_1: sd::ble_gap_sec_params_t::new_bitfield_1(
+                1, // bond
+                1, // mitm
+                1, // lesc
+                0, // keypress
+                sd::BLE_GAP_IO_CAPS_DISPLAY_ONLY as u8,
+                0, // oob
+            ),
+            min_key_size: 7,
+            max_key_size: 16,
+            // We distribute the LTK for legacy pairing, the central its
+            // identity to resolve its private addresses
+            kdist_own: sd::ble_gap_sec_kdist_t {
+                _bitfield_1: sd::ble_gap_sec_kdist_t::new_bitfield_1(1, 0, 0, 0),
+            },
+            kdist_peer: sd::ble_gap_sec_kdist_t {
+                _bitfield_1: sd::ble_gap_sec_kdist_t::new_bitfield_1(0, 1, 0, 0),
+            },
+        };
+        self.peer_id_key = unsafe { core::mem::zeroed() };
+        // Has to stay valid until AUTH_STATUS, hence stored in self
+        self.keyset = sd::ble_gap_sec_keyset_t {
+            keys_own: sd::ble_gap_sec_keys_t {
+                p_enc_key: &mut self.own_enc_key,
+                p_id_key: core::ptr::null_mut(),
+                p_sign_key: core::ptr::null_mut(),
+                p_pk: &mut self.own_pk,
+            },
+            keys_peer: sd::ble_gap_sec_keys_t {
+                p_enc_key: core::ptr::null_mut(),
+                p_id_key: &mut self.peer_id_key,
+                p_sign_key: core::ptr::null_mut(),
+                p_pk: &mut self.peer_pk,
+            },
+        };
+        SdError::check(unsafe {
+            sd::sd_ble_gap_sec_params_reply(
+                conn_handle,
+                sd::BLE_GAP_SEC_STATUS_SUCCESS as u8,
+                &sec_params,
+                &self.keyset,
+            )
+        })
+    }
+
+    /// Computes the LESC Diffie-Hellman key from the central's public key.
+    /// This takes a while on the Cortex-M4.
+    pub fn on_lesc_dhkey_request(&mut self, conn_handle: u16) -> Result<(), SdError> {
+        let mut dhkey = sd::ble_gap_lesc_dhkey_t { key: [0; 32] };
+        // SEC1 encoding of the peer's key, which is in little endian
+        let mut peer_sec1 = [0u8; 65];
+        peer_sec1[0] = 0x04;
+        peer_sec1[1..33].copy_from_slice(&self.peer_pk.pk[..32]);
+        peer_sec1[1..33].reverse();
+        peer_sec1[33..].copy_from_slice(&self.peer_pk.pk[32..]);
+        peer_sec1[33..].reverse();
+        match (&self.secret_key, PublicKey::from_sec1_bytes(&peer_sec1)) {
+            (Some(secret_key), Ok(peer_key)) => {
+                let shared = p256::elliptic_curve::ecdh::diffie_hellman(
+                    secret_key.to_nonzero_scalar(),
+                    peer_key.as_affine(),
+                );
+                dhkey.key.copy_from_slice(shared.as_bytes());
+                dhkey.key.reverse();
+            }
+            _ => {
+                // Invalid key, a random DHKey makes the pairing fail
+                defmt::warn!("Invalid LESC public key of the central");
+                random_bytes(&mut dhkey.key)?;
+            }
+        }
+        SdError::check(unsafe { sd::sd_ble_gap_lesc_dhkey_reply(conn_handle, &dhkey) })
+    }
+
+    pub fn on_passkey_display(&self, passkey: &[u8; 6]) {
+        match core::str::from_utf8(passkey) {
+            Ok(passkey) => defmt::info!("Passkey: {=str}", passkey),
+            Err(_) => defmt::error!("Passkey is not ASCII: {:x}", passkey),
+        }
+    }
+
+    /// Stores the bond after successful pairing. `peer_addr` is used as
+    /// identity address if the central didn't distribute one.
+    pub fn on_auth_status(
+        &mut self,
+        success: bool,
+        bonded: bool,
+        peer: &PeerAddr,
+    ) -> Result<(), flash::FlashError> {
+        if !success || !bonded {
+            return Ok(());
+        }
+        let id_addr = &self.peer_id_key.id_addr_info;
+        let (addr_type, addr) = if id_addr.addr == [0; 6] {
+            (peer.addr_type.raw(), peer.addr)
+        } else {
+            (id_addr.addr_type(), id_addr.addr)
+        };
+        let enc_info = &self.own_enc_key.enc_info;
+        let master_id = &self.own_enc_key.master_id;
+        self.bonds.insert(Bond {
+            addr_type,
+            addr,
+            irk: self.peer_id_key.id_info.irk,
+            ltk: enc_info.ltk,
+            ltk_len: enc_info.ltk_len(),
+            lesc: enc_info.lesc() != 0,
+            auth: enc_info.auth() != 0,
+            ediv: master_id.ediv,
+            rand: master_id.rand,
+        });
+        self.identities_outdated = true;
+        self.save()
+    }
+
+    /// Looks up the key for re-encrypting the link with a bonded central,
+    /// replies without key if there's none.
+    pub fn on_sec_info_request(
+        &mut self,
+        conn_handle: u16,
+        peer: &PeerAddr,
+        ediv: u16,
+        rand: &[u8; 8],
+    ) -> Result<(), SdError> {
+        // Legacy keys are identified by EDIV and Rand, LESC ones by the
+        // identity address of the central
+        let bond = if ediv != 0 || rand != &[0; 8] {
+            self.bonds.find_by_master_id(ediv, rand)
+        } else {
+            self.bonds.find_by_addr(peer.addr_type.raw(), &peer.addr)
+        };
+        let mut enc_info: sd::ble_gap_enc_info_t = unsafe { core::mem::zeroed() };
+        let p_enc_info = match bond {
+            Some(bond) => {
+                enc_info.ltk = bond.ltk;
+                enc_info.set_ltk_len(bond.ltk_len);
+                enc_info.set_lesc(bond.lesc as u8);
+                enc_info.set_auth(bond.auth as u8);
+                &enc_info as *const sd::ble_gap_enc_info_t
+            }
+            None => {
+                defmt::info!("No bond found, the central has to pair again.");
+                core::ptr::null()
+            }
+        };
+        SdError::check(unsafe {
+            sd::sd_ble_gap_sec_info_reply(
+                conn_handle,
+                p_enc_info,
+                core::ptr::null(),
+                core::ptr::null(),
+            )
+        })
+    }
+
+    pub fn bonds(&self) -> &BondStore {
+        &self.bonds
+    }
+
+    /// Forgets all bonds.
+    pub fn clear_bonds(&mut self) -> Result<(), flash::FlashError> {
+        self.bonds.clear();
+        self.identities_outdated = true;
+        self.save()
+    }
+
+    /// Writes the bonds to the other page. Its header is written last, so
+    /// the current page stays valid until the new one is complete.
+    fn save(&mut self) -> Result<(), flash::FlashError> {
+        let page = (self.page + 1) % PAGES;
+        let seq = self.seq.wrapping_add(1);
+        let mut words = [0u32; MAX_BONDS * Bond::WORDS];
+        let len = self.bonds.encode(&mut words);
+        flash::erase_page(page_addr(page))?;
+        flash::write(page_addr(page) + PAGE_HEADER_LEN as u32, &words[..len])?;
+        flash::write(page_addr(page), &flash::page_header(bond::PAGE_MAGIC, seq))?;
+        self.page = page;
+        self.seq = seq;
+        Ok(())
+    }
+}
+
+impl Default for Security {
+    fn default() -> Self {
+        Security::new()
+    }
+}
+
+/// Fills `buf` from the SoftDevice's random number pool.
+fn random_bytes(buf: &mut [u8]) -> Result<(), SdError> {
+    for chunk in buf.chunks_mut(8) {
+        loop {
+            match SdError::check(unsafe {
+                sd::sd_rand_application_vector_get(chunk.as_mut_ptr(), chunk.len() as u8)
+            }) {
+                Ok(()) => break,
+                // Pool not refilled yet
+                Err(SdError::SocRandNotEnoughValues) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
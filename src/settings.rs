@@ -0,0 +1,308 @@
+//! Tunables which can be changed without rebuilding, and their encoding in
+//! flash.
+//!
+//! Settings are stored as a log of records, one per changed value, appended
+//! to a flash page until it's full. Then the current values are compacted
+//! into the next page, see `settings_store`. This way, a page is only erased
+//! every few dozen changes and the erases are spread over all pages.
+//!
+//! A page starts with `PAGE_MAGIC` and a sequence number, the page with the
+//! highest one holds the current records, see `flash::page_seq()`. Each
+//! record is protected by a CRC, so a write interrupted by a reset doesn't
+//! corrupt the settings.
+
+use crate::flash::PAGE_HEADER_LEN;
+use crate::ramp::RampLimits;
+use core::fmt::Write;
+use heapless::String;
+
+pub const PAGE_MAGIC: u32 = 0x5345_5454; // "SETT"
+                                         // Replies of the configuration characteristic carrying the name have to
+                                         // fit into a notification with the default ATT MTU, see `config`
+pub const MAX_NAME_LEN: usize = 18;
+pub const MAX_VALUE_LEN: usize = MAX_NAME_LEN;
+/// Header, value and CRC
+pub const MAX_RECORD_WORDS: usize = 1 + (MAX_VALUE_LEN + 3) / 4 + 1;
+
+const RECORD_MARKER: u8 = 0xA5;
+const ERASED: u32 = 0xFFFF_FFFF;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Key {
+    DeviceName = 1,
+    PwmFreq = 2,
+    MaxSpeed = 3,
+    RampLimits = 4,
+    FailsafeTimeout = 5,
+    MotorInversion = 6,
+}
+
+impl Key {
+    pub const ALL: [Key; 6] = [
+        Key::DeviceName,
+        Key::PwmFreq,
+        Key::MaxSpeed,
+        Key::RampLimits,
+        Key::FailsafeTimeout,
+        Key::MotorInversion,
+    ];
+
+    pub fn from_raw(key: u8) -> Option<Key> {
+        Key::ALL.iter().copied().find(|k| *k as u8 == key)
+    }
+
+    /// Version of the value encoding. Records with another version are
+    /// ignored, so the setting falls back to its default after changing
+    /// the encoding.
+    pub fn version(self) -> u8 {
+        1
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum SettingsError {
+    UnknownKey,
+    InvalidLength,
+    OutOfRange,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Settings {
+    /// Empty to derive the name from the device address
+    pub device_name: String<MAX_NAME_LEN>,
+    pub pwm_freq_hz: u32,
+    /// Speeds from the central are limited to `-max_speed..=max_speed`
+    pub max_speed: u8,
+    pub ramp_limits: RampLimits,
+    /// Motors are stopped if no speed value was written for this long
+    pub failsafe_timeout_ms: u32,
+    pub invert_left: bool,
+    pub invert_right: bool,
+}
+
+impl Settings {
+    pub const DEFAULT: Settings = Settings {
+        device_name: String::new(),
+        pwm_freq_hz: 2000,
+        max_speed: 127,
+        ramp_limits: RampLimits {
+            accel: 3,
+            decel: 6,
+            reversal: 4,
+        },
+        failsafe_timeout_ms: 1000,
+        invert_left: false,
+        invert_right: false,
+    };
+
+    /// Encodes a value into `buf`, returns its length:
+    ///
+    /// | Key               | Encoding                               |
+    /// |-------------------|----------------------------------------|
+    /// | `DeviceName`      | UTF-8, up to `MAX_NAME_LEN` bytes      |
+    /// | `PwmFreq`         | u32 Hz, little endian, 100..=20000     |
+    /// | `MaxSpeed`        | u8, 1..=127                            |
+    /// | `RampLimits`      | u8 accel, decel, reversal, each >= 1   |
+    /// | `FailsafeTimeout` | u32 ms, little endian, 100..=60000     |
+    /// | `MotorInversion`  | u8, bit 0: left, bit 1: right          |
+    pub fn get(&self, key: Key, buf: &mut [u8; MAX_VALUE_LEN]) -> usize {
+        match key {
+            Key::DeviceName => {
+                let name = self.device_name.as_bytes();
+                buf[..name.len()].copy_from_slice(name);
+                name.len()
+            }
+            Key::PwmFreq => {
+                buf[..4].copy_from_slice(&self.pwm_freq_hz.to_le_bytes());
+                4
+            }
+            Key::MaxSpeed => {
+                buf[0] = self.max_speed;
+                1
+            }
+            Key::RampLimits => {
+                buf[0] = self.ramp_limits.accel;
+                buf[1] = self.ramp_limits.decel;
+                buf[2] = self.ramp_limits.reversal;
+                3
+            }
+            Key::FailsafeTimeout => {
+                buf[..4].copy_from_slice(&self.failsafe_timeout_ms.to_le_bytes());
+                4
+            }
+            Key::MotorInversion => {
+                buf[0] = self.invert_left as u8 | (self.invert_right as u8) << 1;
+                1
+            }
+        }
+    }
+
+    /// Decodes and checks a value encoded as described for `get()`. The
+    /// setting is left unchanged on error.
+    pub fn set(&mut self, key: Key, value: &[u8]) -> Result<(), SettingsError> {
+        let u32_in = |range: core::ops::RangeInclusive<u32>| match value {
+            [a, b, c, d] => {
+                let v = u32::from_le_bytes([*a, *b, *c, *d]);
+                if range.contains(&v) {
+                    Ok(v)
+                } else {
+                    Err(SettingsError::OutOfRange)
+                }
+            }
+            _ => Err(SettingsError::InvalidLength),
+        };
+        match key {
+            Key::DeviceName => {
+                if value.len() > MAX_NAME_LEN {
+                    return Err(SettingsError::InvalidLength);
+                }
+                let name = core::str::from_utf8(value).map_err(|_| SettingsError::OutOfRange)?;
+                self.device_name.clear();
+                // Can't fail, the length was checked above
+                self.device_name.push_str(name).ok();
+            }
+            Key::PwmFreq => self.pwm_freq_hz = u32_in(100..=20_000)?,
+            Key::MaxSpeed => match value {
+                [speed @ 1..=127] => self.max_speed = *speed,
+                [_] => return Err(SettingsError::OutOfRange),
+                _ => return Err(SettingsError::InvalidLength),
+            },
+            Key::RampLimits => match value {
+                [accel, decel, reversal] => {
+                    if *accel == 0 || *decel == 0 || *reversal == 0 {
+                        return Err(SettingsError::OutOfRange);
+                    }
+                    self.ramp_limits = RampLimits {
+                        accel: *accel,
+                        decel: *decel,
+                        reversal: *reversal,
+                    };
+                }
+                _ => return Err(SettingsError::InvalidLength),
+            },
+            Key::FailsafeTimeout => self.failsafe_timeout_ms = u32_in(100..=60_000)?,
+            Key::MotorInversion => match value {
+                [bits @ 0..=3] => {
+                    self.invert_left = bits & 1 != 0;
+                    self.invert_right = bits & 2 != 0;
+                }
+                [_] => return Err(SettingsError::OutOfRange),
+                _ => return Err(SettingsError::InvalidLength),
+            },
+        }
+        Ok(())
+    }
+
+    /// Applies the records of a settings page on top of the current
+    /// values. Returns the offset of the first free word, where the next
+    /// record goes.
+    pub fn read_page(&mut self, page: &[u8]) -> usize {
+        let mut offset = PAGE_HEADER_LEN;
+        while offset + 4 <= page.len() {
+            let header = [
+                page[offset],
+                page[offset + 1],
+                page[offset + 2],
+                page[offset + 3],
+            ];
+            if u32::from_le_bytes(header) == ERASED {
+                break;
+            }
+            let [key, version, len, marker] = header;
+            let len = len as usize;
+            let value_start = offset + 4;
+            let crc_start = value_start + padded(len);
+            if marker != RECORD_MARKER || len > MAX_VALUE_LEN || crc_start + 4 > page.len() {
+                // Not a record we wrote, the rest of the page is unusable
+                return page.len();
+            }
+            let stored_crc = u32::from_le_bytes([
+                page[crc_start],
+                page[crc_start + 1],
+                page[crc_start + 2],
+                page[crc_start + 3],
+            ]);
+            let value = &page[value_start..value_start + len];
+            match Key::from_raw(key) {
+                Some(key)
+                    if stored_crc == record_crc(&header, value) && version == key.version() =>
+                {
+                    if self.set(key, value).is_err() {
+                        defmt::warn!("Ignoring invalid stored setting {}", key);
+                    }
+                }
+                // Interrupted write, unknown key or outdated encoding
+                _ => defmt::debug!("Skipping settings record at offset {}", offset),
+            }
+            offset = crc_start + 4;
+        }
+        offset
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings::DEFAULT
+    }
+}
+
+/// Name used while the `DeviceName` setting is empty, e.g.
+/// "RustyRover-A1B2" for the address A1:B2 (usual notation, ending in
+/// `addr[1]`, `addr[0]` as the address is little endian).
+pub fn default_device_name(addr: &[u8; 6]) -> String<MAX_NAME_LEN> {
+    let mut name = String::new();
+    // Can't fail, the name is 15 characters long
+    write!(name, "RustyRover-{:02X}{:02X}", addr[1], addr[0]).ok();
+    name
+}
+
+/// Encodes a record into `words`, returns the number of words used:
+///
+/// | Offset | Size | Content                                  |
+/// |--------|------|------------------------------------------|
+/// | 0      | 1    | Key                                      |
+/// | 1      | 1    | Version of the value encoding            |
+/// | 2      | 1    | Value length n                           |
+/// | 3      | 1    | 0xA5                                     |
+/// | 4      | n    | Value, padded to full words with 0xFF    |
+/// | 4 + p  | 4    | CRC-32 of header and value, little endian|
+pub fn encode_record(key: Key, value: &[u8], words: &mut [u32; MAX_RECORD_WORDS]) -> usize {
+    let header = [key as u8, key.version(), value.len() as u8, RECORD_MARKER];
+    let mut bytes = [0xFFu8; MAX_RECORD_WORDS * 4];
+    bytes[..4].copy_from_slice(&header);
+    bytes[4..4 + value.len()].copy_from_slice(value);
+    let crc_start = 4 + padded(value.len());
+    bytes[crc_start..crc_start + 4].copy_from_slice(&record_crc(&header, value).to_le_bytes());
+    let len = crc_start / 4 + 1;
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)).take(len) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    len
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) / 4 * 4
+}
+
+fn record_crc(header: &[u8; 4], value: &[u8]) -> u32 {
+    !crc32_update(crc32_update(!0, header), value)
+}
+
+/// CRC-32 as used by Ethernet and zlib.
+pub fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,146 @@
+//! Settings in flash, see `settings` for the format.
+//!
+//! The store uses the pages of `SETTINGS` in memory.x in turns. Loading
+//! only reads memory and works before the SoftDevice is enabled, saving
+//! goes through the SoftDevice's flash API and requires it to be enabled.
+
+use crate::flash::{self, FlashError, PAGE_HEADER_LEN, PAGE_SIZE};
+use crate::settings::{self, Key, Settings, MAX_RECORD_WORDS, MAX_VALUE_LEN};
+
+const PAGES: usize = 2;
+
+extern "C" {
+    // Defined in memory.x
+    static __settings_start: u32;
+}
+
+fn page_addr(page: usize) -> u32 {
+    unsafe { &__settings_start as *const u32 as u32 + page as u32 * PAGE_SIZE }
+}
+
+fn page_bytes(page: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(page_addr(page) as *const u8, PAGE_SIZE as usize) }
+}
+
+pub struct SettingsStore {
+    // Page holding the current records
+    page: usize,
+    seq: u32,
+    // Offset of the next record in the page, 0 if no page is in use yet
+    end: usize,
+    // Values as stored, only changes are written
+    stored: Settings,
+}
+
+impl SettingsStore {
+    /// Reads the settings from flash. Settings never saved, or saved with
+    /// an outdated encoding, have their default value.
+    pub fn load() -> (SettingsStore, Settings) {
+        let current = (0..PAGES)
+            .filter_map(|page| {
+                flash::page_seq(page_bytes(page), settings::PAGE_MAGIC).map(|seq| (page, seq))
+            })
+            .max_by_key(|(_, seq)| *seq);
+        let mut stored = Settings::default();
+        let store = match current {
+            Some((page, seq)) => {
+                let end = stored.read_page(page_bytes(page));
+                defmt::info!("Settings loaded from page {} (sequence {}).", page, seq);
+                SettingsStore {
+                    page,
+                    seq,
+                    end,
+                    stored: stored.clone(),
+                }
+            }
+            None => {
+                defmt::info!("No settings stored, using defaults.");
+                SettingsStore {
+                    // Compaction moves on to page 0
+                    page: PAGES - 1,
+                    seq: 0,
+                    end: 0,
+                    stored: stored.clone(),
+                }
+            }
+        };
+        (store, stored)
+    }
+
+    /// Writes the settings which differ from the stored ones.
+    pub fn save(&mut self, settings: &Settings) -> Result<(), FlashError> {
+        let mut words = [0u32; MAX_RECORD_WORDS];
+        let mut value = [0u8; MAX_VALUE_LEN];
+        let mut stored_value = [0u8; MAX_VALUE_LEN];
+        for key in Key::ALL {
+            let len = settings.get(key, &mut value);
+            let stored_len = self.stored.get(key, &mut stored_value);
+            if value[..len] == stored_value[..stored_len] {
+                continue;
+            }
+            let record_len = settings::encode_record(key, &value[..len], &mut words) * 4;
+            if self.end == 0 || self.end + record_len > PAGE_SIZE as usize {
+                return self.compact(settings);
+            }
+            flash::write(
+                page_addr(self.page) + self.end as u32,
+                &words[..record_len / 4],
+            )?;
+            self.end += record_len;
+            self.stored.set(key, &value[..len]).ok();
+        }
+        Ok(())
+    }
+
+    /// Writes only `key` of `settings`, other changes stay unsaved.
+    pub fn save_key(&mut self, key: Key, settings: &Settings) -> Result<(), FlashError> {
+        let mut value = [0u8; MAX_VALUE_LEN];
+        let len = settings.get(key, &mut value);
+        let mut to_save = self.stored.clone();
+        // Taken from valid settings, can't be rejected
+        to_save.set(key, &value[..len]).ok();
+        self.save(&to_save)
+    }
+
+    /// Erases the settings, all of them are back to default after the next
+    /// `load()`.
+    pub fn reset(&mut self) -> Result<(), FlashError> {
+        self.compact(&Settings::default())
+    }
+
+    /// Writes all settings which aren't at their default to the next page.
+    /// The page header is written last, so the current page stays valid
+    /// until the new one is complete.
+    fn compact(&mut self, settings: &Settings) -> Result<(), FlashError> {
+        let page = (self.page + 1) % PAGES;
+        let seq = self.seq.wrapping_add(1);
+        flash::erase_page(page_addr(page))?;
+
+        let defaults = Settings::default();
+        let mut words = [0u32; MAX_RECORD_WORDS];
+        let mut value = [0u8; MAX_VALUE_LEN];
+        let mut default_value = [0u8; MAX_VALUE_LEN];
+        let mut end = PAGE_HEADER_LEN;
+        for key in Key::ALL {
+            let len = settings.get(key, &mut value);
+            let default_len = defaults.get(key, &mut default_value);
+            if value[..len] == default_value[..default_len] {
+                continue;
+            }
+            let record_words = settings::encode_record(key, &value[..len], &mut words);
+            flash::write(page_addr(page) + end as u32, &words[..record_words])?;
+            end += record_words * 4;
+        }
+        flash::write(
+            page_addr(page),
+            &flash::page_header(settings::PAGE_MAGIC, seq),
+        )?;
+        defmt::debug!("Settings compacted into page {} (sequence {}).", page, seq);
+
+        self.page = page;
+        self.seq = seq;
+        self.end = end;
+        self.stored = settings.clone();
+        Ok(())
+    }
+}
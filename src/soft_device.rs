@@ -1,8 +1,23 @@
 use crate as _; // global logger + panicking-behavior + memory layout
+use crate::adv::{self, AdvertisingData, FLAG_BR_EDR_NOT_SUPPORTED, FLAG_LE_GENERAL_DISCOVERABLE};
+use crate::ble_event::BleEvent;
+use crate::config::{self, Command};
+use crate::connection::Connection;
+use crate::crash;
+use crate::fault_log::{Fault, FaultKind, FaultLog};
+use crate::flash;
+use crate::gatt::{uuid128, Characteristic, SecurityMode, Service, Uuid};
+use crate::security::Security;
+use crate::settings::{default_device_name, MAX_NAME_LEN};
+use crate::telemetry::Telemetry;
 use aligned::{Aligned, A4};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use heapless::String;
+use nrf52832_hal::pac;
 use nrf_softdevice_s112 as sd;
 
-static BASE_UUID: sd::ble_uuid128_t = sd::ble_uuid128_t {
+const BASE_UUID: sd::ble_uuid128_t = sd::ble_uuid128_t {
     uuid128: [
         // 2 bytes set via ble_uuid_t.uuid (were 0x66, 0xf3)--------------------.-----.
         //                                                                      v     v
@@ -10,132 +25,541 @@ static BASE_UUID: sd::ble_uuid128_t = sd::ble_uuid128_t {
         0x7f,
     ],
 };
-static ROVER_SERVICE_UUID: u16 = 0x0001;
-static ROVER_CHARAC_UUID: u16 = 0x0002;
-
-#[rustfmt::skip]
-static mut ADV_DATA: [u8; 10] = [
-    2, 0x01, 0x06, // flags: 0b00000110 (LE General Discoverable Mode, BR/EDR not supported)
-    //Shortened Local Name:
-    6, 0x08, b'R', b'u', b's', b't', b'y',
-    //Complete Local Name. Apparently mustn't be too long:
-    //6, 0x09, b'R', b'u', b's', b't', b'y',
-    ];
-
-#[rustfmt::skip]
-static mut SCAN_RESP: [u8; 12] = [
-    // Complete Local Name:
-    11, 0x09, b'R', b'u', b's', b't', b'y', b'R', b'o', b'v', b'e', b'r',
-    //1, 0x03, // Complete list of 16-bit Service UUIDs (empty)
-    //1, 0x05, // Complete list of 32-bit Service UUIDs (empty)
-    //1, 0x07, // Complete list of 128-bit Service UUIDs (empty)
-    ];
-
-#[rustfmt::skip]
-static DEV_NAME: [u8; 10] = [b'R', b'u', b's', b't', b'y', b'R', b'o', b'v', b'e', b'r'];
-
-static mut CONN_SEC_MODE_BITFIELD: [u8; 1] = [0];
-
-static mut CONN_SEC_MODE: sd::ble_gap_conn_sec_mode_t = unsafe {
-    sd::ble_gap_conn_sec_mode_t {
-        // Security Mode 1, Level 1 = Open Link. Set in init()
-        _bitfield_1: sd::__BindgenBitfieldUnit::new(CONN_SEC_MODE_BITFIELD),
-    }
+const ROVER_SERVICE_UUID: u16 = 0x0001;
+const ROVER_CHARAC_UUID: u16 = 0x0002;
+const TELEMETRY_CHARAC_UUID: u16 = 0x0003;
+const CONFIG_CHARAC_UUID: u16 = 0x0004;
+const FAULTS_CHARAC_UUID: u16 = 0x0005;
+// Newest faults readable over BLE, their values take up room in the
+// SoftDevice's attribute table
+const MAX_FAULTS_READABLE: usize = 2;
+// Bluetooth SIG assigned numbers
+const BATTERY_SERVICE_UUID: u16 = 0x180F;
+const BATTERY_LEVEL_CHARAC_UUID: u16 = 0x2A19;
+const DEVICE_INFO_SERVICE_UUID: u16 = 0x180A;
+const FIRMWARE_REV_CHARAC_UUID: u16 = 0x2A26;
+const HARDWARE_REV_CHARAC_UUID: u16 = 0x2A27;
+const SOFTWARE_REV_CHARAC_UUID: u16 = 0x2A28;
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+// Set by build.rs
+pub const GIT_HASH: &str = env!("GIT_HASH");
+// Override by setting ROVER_HW_REV when building
+pub const HARDWARE_REVISION: &str = match option_env!("ROVER_HW_REV") {
+    Some(rev) => rev,
+    None => "1",
 };
 
-static CHARAC_DESC: [u8; 8] = [b'r', b'o', b'v', b'e', b'r', b'-', b'i', b'o'];
+// Has to match the RAM origin in memory.x. Not measured for the current
+// configuration yet: init() logs the value sd_ble_enable() reports, which
+// both have to be set to.
+const APP_RAM_BASE: u32 = 0x20000000 + 0x2200;
+// Tag of the connection configuration set in init()
+const CONN_CFG_TAG: u8 = 1;
+/// Largest ATT MTU the SoftDevice is configured for
+pub const MAX_ATT_MTU: u16 = 247;
+
+// Events can carry up to an ATT MTU of data after ble_evt_t, see
+// BLE_EVT_LEN_MAX() in ble.h
+const EVT_BUF_LEN: usize = core::mem::size_of::<sd::ble_evt_t>() + MAX_ATT_MTU as usize;
+
+// Supply voltage below which SocEvent::PowerFailureWarning is raised. The
+// motors can drag VDD down that far before the chip resets.
+const DEFAULT_POF_THRESHOLD: u8 = sd::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V28 as u8;
+
+// Number of notifications the SoftDevice can queue for transmission
+const HVN_TX_QUEUE_SIZE: u8 = sd::BLE_GATTS_HVN_TX_QUEUE_SIZE_DEFAULT as u8;
+
+// Both payloads are limited to 31 bytes. The 128 bit service UUID takes up
+// most of the scan response, so the name goes into the advertising data.
+const _: () = assert!(3 + 2 + MAX_NAME_LEN <= adv::MAX_LEN, "Device name too long");
+
+// Kept in RAM, the SoftDevice reads them while advertising. ADV_DATA is
+// filled with the device name in configure_advertising().
+static mut ADV_DATA: AdvertisingData = AdvertisingData::new();
+// Lets apps filter for the rover while scanning
+static mut SCAN_RESP: AdvertisingData =
+    AdvertisingData::new().services_128(true, &[uuid128(&BASE_UUID.uuid128, ROVER_SERVICE_UUID)]);
+
+/// Error codes returned by SoftDevice calls (`NRF_ERROR_*`, `BLE_ERROR_*`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum SdError {
+    SvcHandlerMissing,
+    SoftDeviceNotEnabled,
+    Internal,
+    NoMem,
+    NotFound,
+    NotSupported,
+    InvalidParam,
+    InvalidState,
+    InvalidLength,
+    InvalidFlags,
+    InvalidData,
+    DataSize,
+    Timeout,
+    Null,
+    Forbidden,
+    InvalidAddr,
+    Busy,
+    ConnCount,
+    Resources,
+    SdmLfclkSourceUnknown,
+    SdmIncorrectInterruptConfiguration,
+    SdmIncorrectClenr0,
+    SocRandNotEnoughValues,
+    BleNotEnabled,
+    BleInvalidConnHandle,
+    BleInvalidAttrHandle,
+    BleInvalidAdvHandle,
+    BleInvalidRole,
+    BleBlockedByOtherLinks,
+    GapUuidListMismatch,
+    GapDiscoverableWithWhitelist,
+    GapInvalidBleAddr,
+    GapWhitelistInUse,
+    GapDeviceIdentitiesInUse,
+    GapDeviceIdentitiesDuplicate,
+    GattcProcNotPermitted,
+    GattsInvalidAttrType,
+    GattsSysAttrMissing,
+    /// Return value not covered by the variants above
+    Unknown(u32),
+}
+
+impl SdError {
+    /// Turns the return value of a SoftDevice call into a `Result`.
+    pub fn check(ret: u32) -> Result<(), SdError> {
+        match ret {
+            sd::NRF_SUCCESS => Ok(()),
+            code => Err(SdError::from_code(code)),
+        }
+    }
+
+    pub fn from_code(code: u32) -> SdError {
+        match code {
+            sd::NRF_ERROR_SVC_HANDLER_MISSING => SdError::SvcHandlerMissing,
+            sd::NRF_ERROR_SOFTDEVICE_NOT_ENABLED => SdError::SoftDeviceNotEnabled,
+            sd::NRF_ERROR_INTERNAL => SdError::Internal,
+            sd::NRF_ERROR_NO_MEM => SdError::NoMem,
+            sd::NRF_ERROR_NOT_FOUND => SdError::NotFound,
+            sd::NRF_ERROR_NOT_SUPPORTED => SdError::NotSupported,
+            sd::NRF_ERROR_INVALID_PARAM => SdError::InvalidParam,
+            sd::NRF_ERROR_INVALID_STATE => SdError::InvalidState,
+            sd::NRF_ERROR_INVALID_LENGTH => SdError::InvalidLength,
+            sd::NRF_ERROR_INVALID_FLAGS => SdError::InvalidFlags,
+            sd::NRF_ERROR_INVALID_DATA => SdError::InvalidData,
+            sd::NRF_ERROR_DATA_SIZE => SdError::DataSize,
+            sd::NRF_ERROR_TIMEOUT => SdError::Timeout,
+            sd::NRF_ERROR_NULL => SdError::Null,
+            sd::NRF_ERROR_FORBIDDEN => SdError::Forbidden,
+            sd::NRF_ERROR_INVALID_ADDR => SdError::InvalidAddr,
+            sd::NRF_ERROR_BUSY => SdError::Busy,
+            sd::NRF_ERROR_CONN_COUNT => SdError::ConnCount,
+            sd::NRF_ERROR_RESOURCES => SdError::Resources,
+            sd::NRF_ERROR_SDM_LFCLK_SOURCE_UNKNOWN => SdError::SdmLfclkSourceUnknown,
+            sd::NRF_ERROR_SDM_INCORRECT_INTERRUPT_CONFIGURATION => {
+                SdError::SdmIncorrectInterruptConfiguration
+            }
+            sd::NRF_ERROR_SDM_INCORRECT_CLENR0 => SdError::SdmIncorrectClenr0,
+            sd::NRF_ERROR_SOC_RAND_NOT_ENOUGH_VALUES => SdError::SocRandNotEnoughValues,
+            sd::BLE_ERROR_NOT_ENABLED => SdError::BleNotEnabled,
+            sd::BLE_ERROR_INVALID_CONN_HANDLE => SdError::BleInvalidConnHandle,
+            sd::BLE_ERROR_INVALID_ATTR_HANDLE => SdError::BleInvalidAttrHandle,
+            sd::BLE_ERROR_INVALID_ADV_HANDLE => SdError::BleInvalidAdvHandle,
+            sd::BLE_ERROR_INVALID_ROLE => SdError::BleInvalidRole,
+            sd::BLE_ERROR_BLOCKED_BY_OTHER_LINKS => SdError::BleBlockedByOtherLinks,
+            sd::BLE_ERROR_GAP_UUID_LIST_MISMATCH => SdError::GapUuidListMismatch,
+            sd::BLE_ERROR_GAP_DISCOVERABLE_WITH_WHITELIST => SdError::GapDiscoverableWithWhitelist,
+            sd::BLE_ERROR_GAP_INVALID_BLE_ADDR => SdError::GapInvalidBleAddr,
+            sd::BLE_ERROR_GAP_WHITELIST_IN_USE => SdError::GapWhitelistInUse,
+            sd::BLE_ERROR_GAP_DEVICE_IDENTITIES_IN_USE => SdError::GapDeviceIdentitiesInUse,
+            sd::BLE_ERROR_GAP_DEVICE_IDENTITIES_DUPLICATE => SdError::GapDeviceIdentitiesDuplicate,
+            sd::BLE_ERROR_GATTC_PROC_NOT_PERMITTED => SdError::GattcProcNotPermitted,
+            sd::BLE_ERROR_GATTS_INVALID_ATTR_TYPE => SdError::GattsInvalidAttrType,
+            sd::BLE_ERROR_GATTS_SYS_ATTR_MISSING => SdError::GattsSysAttrMissing,
+            other => SdError::Unknown(other),
+        }
+    }
+}
+
+/// Step of `SoftDevice::init()` which failed, along with the reason.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub enum InitError {
+    SoftDeviceEnable(SdError),
+    PowerFailureConfig(SdError),
+    BleConfig(SdError),
+    BleEnable(SdError),
+    Security(SdError),
+    DeviceNameSet(SdError),
+    UuidAdd(SdError),
+    ServiceAdd(SdError),
+    CharacteristicAdd(SdError),
+    DeviceInfoAdd(SdError),
+    AdvConfigure(SdError),
+    AdvStart(SdError),
+}
+
+/// Identification of the flashed SoftDevice, read from its info structure.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct SoftDeviceInfo {
+    /// 112 for S112
+    pub id: u32,
+    pub major: u8,
+    pub minor: u8,
+    pub bugfix: u16,
+    /// Firmware ID, unique for each SoftDevice release
+    pub fwid: u16,
+}
+
+impl SoftDeviceInfo {
+    // See SD_OFFSET_GET() and friends in nrf_sdm.h
+    const INFO_STRUCT_ADDR: usize = 0x1000 + 0x2000;
+    const MAGIC_NUMBER: u32 = 0x51B1E5DB;
+
+    /// Returns `None` if no SoftDevice is flashed.
+    pub fn read() -> Option<SoftDeviceInfo> {
+        let read = |offset: usize| unsafe {
+            core::ptr::read_volatile((Self::INFO_STRUCT_ADDR + offset) as *const u32)
+        };
+        if read(0x04) != Self::MAGIC_NUMBER {
+            return None;
+        }
+        // Version is encoded as MMmmmbbb in decimal
+        let version = read(0x14);
+        Some(SoftDeviceInfo {
+            id: read(0x10),
+            major: (version / 1_000_000) as u8,
+            minor: (version / 1000 % 1000) as u8,
+            bugfix: (version % 1000) as u16,
+            fwid: read(0x0C) as u16,
+        })
+    }
+}
+
+fn is_enabled() -> bool {
+    let mut enabled = 0u8;
+    let result = unsafe { sd::sd_softdevice_is_enabled(&mut enabled) };
+    result == sd::NRF_SUCCESS && enabled != 0
+}
+
+/// Sleeps until an interrupt or event. Once the SoftDevice is enabled, this
+/// goes through it, as it handles some of its events on wake-up.
+pub fn wait_for_event() {
+    if is_enabled() {
+        if let Err(e) = SdError::check(unsafe { sd::sd_app_evt_wait() }) {
+            defmt::error!("sd_app_evt_wait failed: {}", e);
+        }
+    } else {
+        cortex_m::asm::wfe();
+    }
+}
+
+/// How `nrf_fault_handler` deals with SoftDevice assertions, see
+/// `set_fault_policy()`.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct FaultPolicy {
+    /// P0 pins driven low first, e.g. the motor driver's inputs and standby
+    pub safe_low_pins: u32,
+    /// Halt instead of resetting, to keep the state for a debugger
+    pub halt: bool,
+}
+
+impl FaultPolicy {
+    pub const DEFAULT: FaultPolicy = FaultPolicy {
+        safe_low_pins: 0,
+        halt: cfg!(debug_assertions),
+    };
+}
+
+impl Default for FaultPolicy {
+    fn default() -> Self {
+        FaultPolicy::DEFAULT
+    }
+}
+
+// The fault handler can't use RTIC resources, the SoftDevice may have
+// asserted within any of them
+static FAULT_SAFE_LOW_PINS: AtomicU32 = AtomicU32::new(FaultPolicy::DEFAULT.safe_low_pins);
+static FAULT_HALT: AtomicBool = AtomicBool::new(FaultPolicy::DEFAULT.halt);
+
+pub fn set_fault_policy(policy: FaultPolicy) {
+    FAULT_SAFE_LOW_PINS.store(policy.safe_low_pins, Ordering::Relaxed);
+    FAULT_HALT.store(policy.halt, Ordering::Relaxed);
+}
 
+/* The SoftDevice is unusable after an assertion, the only way out is a
+ * reset. The fault is stored and logged at the next boot, see crash.
+ */
 #[no_mangle]
 extern "C" fn nrf_fault_handler(id: u32, pc: u32, info: u32) {
+    force_safe_outputs(FAULT_SAFE_LOW_PINS.load(Ordering::Relaxed));
     defmt::error!(
         "nrf hard fault! ID: 0x{:08x}, PC: 0x{:08x}, INFO: 0x{:08x}",
         id,
         pc,
         info
     );
+    crash::record(&Fault::with_data(FaultKind::SoftDevice, &[id, pc, info]));
+    if FAULT_HALT.load(Ordering::Relaxed) {
+        loop {
+            cortex_m::asm::bkpt();
+        }
+    }
+    crash::reset()
+}
+
+/// Stops all PWM peripherals and drives `pins` low, bypassing their
+/// drivers.
+fn force_safe_outputs(pins: u32) {
+    for pwm in [pac::PWM0::ptr(), pac::PWM1::ptr(), pac::PWM2::ptr()] {
+        // Returns the pins to their GPIO configuration
+        unsafe { (*pwm).enable.write(|w| w.enable().disabled()) };
+    }
+    let p0 = unsafe { &*pac::P0::ptr() };
+    p0.outclr.write(|w| unsafe { w.bits(pins) });
+    p0.dirset.write(|w| unsafe { w.bits(pins) });
+}
+
+/// Events of the SoftDevice's System on Chip module, read with
+/// `sd_evt_get()`.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SocEvent {
+    HfclkStarted,
+    /// Supply voltage dropped below the threshold set with
+    /// `sd_power_pof_threshold_set()`
+    PowerFailureWarning,
+    FlashOperationSuccess,
+    FlashOperationError,
+    RadioBlocked,
+    RadioCanceled,
+    RadioSignalCallbackInvalidReturn,
+    RadioSessionIdle,
+    RadioSessionClosed,
+    Other(u32),
+}
+
+impl SocEvent {
+    pub fn from_raw(evt_id: u32) -> SocEvent {
+        match evt_id {
+            sd::NRF_SOC_EVTS_NRF_EVT_HFCLKSTARTED => SocEvent::HfclkStarted,
+            sd::NRF_SOC_EVTS_NRF_EVT_POWER_FAILURE_WARNING => SocEvent::PowerFailureWarning,
+            sd::NRF_SOC_EVTS_NRF_EVT_FLASH_OPERATION_SUCCESS => SocEvent::FlashOperationSuccess,
+            sd::NRF_SOC_EVTS_NRF_EVT_FLASH_OPERATION_ERROR => SocEvent::FlashOperationError,
+            sd::NRF_SOC_EVTS_NRF_EVT_RADIO_BLOCKED => SocEvent::RadioBlocked,
+            sd::NRF_SOC_EVTS_NRF_EVT_RADIO_CANCELED => SocEvent::RadioCanceled,
+            sd::NRF_SOC_EVTS_NRF_EVT_RADIO_SIGNAL_CALLBACK_INVALID_RETURN => {
+                SocEvent::RadioSignalCallbackInvalidReturn
+            }
+            sd::NRF_SOC_EVTS_NRF_EVT_RADIO_SESSION_IDLE => SocEvent::RadioSessionIdle,
+            sd::NRF_SOC_EVTS_NRF_EVT_RADIO_SESSION_CLOSED => SocEvent::RadioSessionClosed,
+            other => SocEvent::Other(other),
+        }
+    }
+}
+
+/// Drains the SoC event queue. Flash events complete the operation
+/// `flash` waits for, then every event is passed to `cb`.
+///
+/// Runs from the SoftDevice's event interrupt, at a higher priority than
+/// the tasks using `flash`. `cb` should only hand the event on, e.g. by
+/// spawning a task. Does nothing while the SoftDevice is disabled.
+pub fn handle_soc_events(mut cb: impl FnMut(SocEvent)) {
+    if !is_enabled() {
+        return;
+    }
+    loop {
+        let mut evt_id = 0;
+        match SdError::check(unsafe { sd::sd_evt_get(&mut evt_id) }) {
+            Ok(()) => {
+                let event = SocEvent::from_raw(evt_id);
+                flash::on_soc_event(event);
+                cb(event);
+            }
+            // Queue is empty
+            Err(SdError::NotFound) => break,
+            Err(e) => {
+                defmt::error!("sd_evt_get() failed: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+/// Advertising intervals: fast after boot and disconnects, so the rover is
+/// found quickly, slow afterwards to save power.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct AdvSchedule {
+    /// 20..=10240 ms
+    pub fast_interval_ms: u16,
+    /// Time until switching to `slow_interval_ms`
+    pub fast_duration_s: u16,
+    pub slow_interval_ms: u16,
+}
+
+impl AdvSchedule {
+    pub const DEFAULT: AdvSchedule = AdvSchedule {
+        fast_interval_ms: 40,
+        fast_duration_s: 30,
+        slow_interval_ms: 1000,
+    };
+}
+
+/// How requests of the central to change the link are answered.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct LinkPreferences {
+    /// ATT MTU offered in MTU exchanges, `23..=MAX_ATT_MTU`
+    pub att_mtu: u16,
+    /// PHYs accepted in PHY updates, bit mask of `BLE_GAP_PHY_*`.
+    /// `BLE_GAP_PHY_AUTO` lets the SoftDevice choose.
+    pub phys: u8,
+}
+
+impl LinkPreferences {
+    pub const DEFAULT: LinkPreferences = LinkPreferences {
+        att_mtu: MAX_ATT_MTU,
+        phys: sd::BLE_GAP_PHY_AUTO as u8,
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum AdvMode {
+    Fast,
+    Slow,
 }
 
 pub struct SoftDevice {
     base_uuid_type: u8,
-    rover_service_handle: u16,
-    charac_handle: sd::ble_gatts_char_handles_t,
+    rover_io: Characteristic,
+    telemetry: Characteristic,
+    config: Characteristic,
+    faults: Characteristic,
+    // As configured, empty for the default name derived from the address
+    device_name: String<MAX_NAME_LEN>,
+    addr: [u8; 6],
+    battery_level: Characteristic,
+    battery_level_value: u8,
+    adv_handle: u8,
+    adv_schedule: AdvSchedule,
+    adv_mode: AdvMode,
+    link_preferences: LinkPreferences,
+    pof_threshold: u8,
+    security: Security,
+    // Required to write rover_io and config, the central has to pair first
+    // unless Open
+    rover_io_security: SecurityMode,
+    connection: Option<Connection>,
+    // Free entries in the SoftDevice's notification queue
+    hvn_tx_free: u8,
     speed_update_cb: fn(i8, i8),
+    disconnect_cb: fn(),
+    config_cb: fn(Command) -> bool,
+    device_name_cb: fn(String<MAX_NAME_LEN>),
 }
 
 impl SoftDevice {
-    pub fn new(speed_update_cb: fn(i8, i8)) -> SoftDevice {
+    /// `config_cb` executes the commands written to the configuration
+    /// characteristic and replies via `send_config_reply()`. It returns
+    /// false if it can't take another command right now. `device_name_cb`
+    /// is called with the new name after the central wrote it.
+    pub fn new(
+        speed_update_cb: fn(i8, i8),
+        disconnect_cb: fn(),
+        config_cb: fn(Command) -> bool,
+        device_name_cb: fn(String<MAX_NAME_LEN>),
+    ) -> SoftDevice {
         SoftDevice {
             base_uuid_type: 0xff,
-            rover_service_handle: 0x0000,
-            // Will be written during sd_ble_gatts_characteristic_add()
-            charac_handle: sd::ble_gatts_char_handles_t {
-                value_handle: 0,
-                user_desc_handle: 0,
-                cccd_handle: 0,
-                sccd_handle: 0,
-            },
+            // Handles are assigned in init()
+            rover_io: Characteristic::default(),
+            telemetry: Characteristic::default(),
+            config: Characteristic::default(),
+            faults: Characteristic::default(),
+            device_name: String::new(),
+            addr: [0; 6],
+            battery_level: Characteristic::default(),
+            battery_level_value: 0,
+            adv_handle: sd::BLE_GAP_ADV_SET_HANDLE_NOT_SET as u8,
+            adv_schedule: AdvSchedule::DEFAULT,
+            adv_mode: AdvMode::Fast,
+            link_preferences: LinkPreferences::DEFAULT,
+            pof_threshold: DEFAULT_POF_THRESHOLD,
+            security: Security::new(),
+            rover_io_security: SecurityMode::EncryptedMitm,
+            connection: None,
+            hvn_tx_free: 0,
             speed_update_cb: speed_update_cb,
+            disconnect_cb: disconnect_cb,
+            config_cb: config_cb,
+            device_name_cb: device_name_cb,
         }
     }
 
-    pub fn init(&mut self) -> bool {
-        // Security Mode 1, Level 1 = Open Link.
-        unsafe {
-            CONN_SEC_MODE.set_lv(1);
-            CONN_SEC_MODE.set_sm(1);
-        }
-
+    pub fn init(&mut self) -> Result<(), InitError> {
         const SD_CLK_CONF: sd::nrf_clock_lf_cfg_t = sd::nrf_clock_lf_cfg_t {
             source: sd::NRF_CLOCK_LF_SRC_XTAL as u8,
             rc_ctiv: 0,
             rc_temp_ctiv: 0,
             accuracy: sd::NRF_CLOCK_LF_ACCURACY_50_PPM as u8,
         };
-        let retval = unsafe { sd::sd_softdevice_enable(&SD_CLK_CONF, Some(nrf_fault_handler)) };
-        match retval {
-            sd::NRF_SUCCESS => defmt::debug!("SoftDevice enabled successfully!"),
-            _ => {
-                defmt::error!("Failed to enable SoftDevice!");
-                return false;
-            }
-        };
+        SdError::check(unsafe { sd::sd_softdevice_enable(&SD_CLK_CONF, Some(nrf_fault_handler)) })
+            .map_err(InitError::SoftDeviceEnable)?;
+        defmt::debug!("SoftDevice enabled successfully!");
 
-        let mut app_ram_base: u32 = 0x20000000 + 0x1AE0;
+        SdError::check(unsafe { sd::sd_power_pof_threshold_set(self.pof_threshold) })
+            .and_then(|()| SdError::check(unsafe { sd::sd_power_pof_enable(1) }))
+            .map_err(InitError::PowerFailureConfig)?;
 
-        /*
-        let mtu_config = sd::ble_cfg_t {
-            conn_cfg: sd::ble_conn_cfg_t {
-                conn_cfg_tag: 1,
-                params: sd::ble_conn_cfg_t__bindgen_ty_1 {
-                    gatt_conn_cfg: sd::ble_gatt_conn_cfg_t { att_mtu: 128 },
-                },
-            },
-        };
+        let mut app_ram_base: u32 = APP_RAM_BASE;
 
-        if unsafe {
+        // Limits the name the central may write to what fits into the
+        // advertising data
+        let mut name_config: sd::ble_cfg_t = unsafe { core::mem::zeroed() };
+        unsafe {
+            let name_cfg = name_config.gap_cfg.as_mut().device_name_cfg.as_mut();
+            name_cfg.write_perm = self.rover_io_security.raw();
+            name_cfg.set_vloc(sd::BLE_GATTS_VLOC_STACK as u8);
+            name_cfg.max_len = MAX_NAME_LEN as u16;
+        }
+        SdError::check(unsafe {
             sd::sd_ble_cfg_set(
-                sd::BLE_CONN_CFGS_BLE_CONN_CFG_GAP,
-                &mtu_config,
+                sd::BLE_GAP_CFGS_BLE_GAP_CFG_DEVICE_NAME,
+                &name_config,
                 app_ram_base,
             )
-        } != sd::NRF_SUCCESS
-        {
-            defmt::error!("sd_ble_cfg_set() failed!");
-            return false;
+        })
+        .map_err(InitError::BleConfig)?;
+
+        // ble_cfg_t is a union, set the connection configuration member
+        let mut gatt_config: sd::ble_cfg_t = unsafe { core::mem::zeroed() };
+        unsafe {
+            let conn_cfg = gatt_config.conn_cfg.as_mut();
+            conn_cfg.conn_cfg_tag = CONN_CFG_TAG;
+            conn_cfg.params.gatt_conn_cfg.as_mut().att_mtu = MAX_ATT_MTU;
         }
-        */
+        SdError::check(unsafe {
+            sd::sd_ble_cfg_set(
+                sd::BLE_CONN_CFGS_BLE_CONN_CFG_GATT,
+                &gatt_config,
+                app_ram_base,
+            )
+        })
+        .map_err(InitError::BleConfig)?;
 
-        match unsafe { sd::sd_ble_enable(&mut app_ram_base) } {
-            sd::NRF_SUCCESS => defmt::debug!("BLE stack enabled successfully!"),
-            _ => {
-                defmt::error!(
-                    "Failed to enable BLE stack! app_ram_base: {:08x}",
-                    app_ram_base
-                );
-                return false;
-            }
-        };
-        defmt::debug!("App RAM base address: 0x{:08x}", app_ram_base);
+        SdError::check(unsafe { sd::sd_ble_enable(&mut app_ram_base) }).map_err(|e| {
+            // On NRF_ERROR_NO_MEM, app_ram_base holds the required value
+            defmt::error!("app_ram_base: 0x{:08x}", app_ram_base);
+            InitError::BleEnable(e)
+        })?;
+        defmt::debug!("BLE stack enabled successfully!");
+        // On success, app_ram_base holds the minimum for the configuration
+        if app_ram_base != APP_RAM_BASE {
+            defmt::warn!(
+                "RAM wasted, set APP_RAM_BASE and memory.x to 0x{:08x}",
+                app_ram_base
+            );
+        }
+
+        self.security.init().map_err(InitError::Security)?;
 
         let mut gap_addr: sd::ble_gap_addr_t = sd::ble_gap_addr_t {
             _bitfield_1: sd::__BindgenBitfieldUnit::default(),
@@ -153,6 +577,7 @@ impl SoftDevice {
             ),
             _ => defmt::error!("Error getting BLE MAC addr!"),
         }
+        self.addr = gap_addr.addr;
 
         let mut appearance = 0u16;
         match unsafe { sd::sd_ble_gap_appearance_get(&mut appearance) } {
@@ -160,18 +585,228 @@ impl SoftDevice {
             _ => defmt::error!("Error getting GAP appearance!"),
         }
 
-        match unsafe {
-            sd::sd_ble_gap_device_name_set(&CONN_SEC_MODE, &DEV_NAME[0], DEV_NAME.len() as u16)
-        } {
-            sd::NRF_SUCCESS => defmt::debug!("Device name set successfully."),
-            _ => {
-                defmt::error!("Error setting device name!");
-                return false;
+        self.apply_device_name().map_err(InitError::DeviceNameSet)?;
+        defmt::info!("Device name: {=str}", self.effective_device_name().as_str());
+
+        SdError::check(unsafe { sd::sd_ble_uuid_vs_add(&BASE_UUID, &mut self.base_uuid_type) })
+            .map_err(InitError::UuidAdd)?;
+        let uuid_type = self.base_uuid_type;
+        let rover_uuid = |uuid| Uuid::Vendor { uuid_type, uuid };
+
+        let rover_service =
+            Service::new(rover_uuid(ROVER_SERVICE_UUID)).map_err(InitError::ServiceAdd)?;
+        self.rover_io = rover_service
+            .characteristic(rover_uuid(ROVER_CHARAC_UUID))
+            .write()
+            .reliable_write()
+            .write_perm(self.rover_io_security)
+            .max_len(2)
+            .user_desc("rover-io")
+            .register()
+            .map_err(InitError::CharacteristicAdd)?;
+        self.telemetry = rover_service
+            .characteristic(rover_uuid(TELEMETRY_CHARAC_UUID))
+            .read()
+            .notify()
+            .value(&Telemetry::default().encode())
+            .user_desc("rover-telemetry")
+            .register()
+            .map_err(InitError::CharacteristicAdd)?;
+        self.config = rover_service
+            .characteristic(rover_uuid(CONFIG_CHARAC_UUID))
+            .read()
+            .write()
+            .notify()
+            .write_perm(self.rover_io_security)
+            .max_len(config::MAX_COMMAND_LEN.max(config::MAX_REPLY_LEN) as u16)
+            .variable_len()
+            .user_desc("rover-config")
+            .register()
+            .map_err(InitError::CharacteristicAdd)?;
+        self.faults = rover_service
+            .characteristic(rover_uuid(FAULTS_CHARAC_UUID))
+            .read()
+            .max_len((MAX_FAULTS_READABLE * Fault::LEN) as u16)
+            .variable_len()
+            .user_desc("rover-faults")
+            .register()
+            .map_err(InitError::CharacteristicAdd)?;
+
+        let battery_service =
+            Service::new(Uuid::Sig(BATTERY_SERVICE_UUID)).map_err(InitError::ServiceAdd)?;
+        self.battery_level = battery_service
+            .characteristic(Uuid::Sig(BATTERY_LEVEL_CHARAC_UUID))
+            .read()
+            .notify()
+            .value(&[self.battery_level_value])
+            .register()
+            .map_err(InitError::CharacteristicAdd)?;
+
+        self.add_device_info_service()
+            .map_err(InitError::DeviceInfoAdd)?;
+
+        self.adv_mode = AdvMode::Fast;
+        self.configure_advertising()
+            .map_err(InitError::AdvConfigure)?;
+        defmt::debug!("Advertisement config successful!");
+
+        self.start_advertising().map_err(InitError::AdvStart)?;
+        defmt::debug!("Advertisement started successfully!");
+
+        defmt::info!(
+            "Firmware {} ({}), hardware revision {}",
+            FIRMWARE_VERSION,
+            GIT_HASH,
+            HARDWARE_REVISION
+        );
+        match SoftDeviceInfo::read() {
+            Some(info) => defmt::info!(
+                "SoftDevice S{} {}.{}.{}, FWID 0x{:04x}",
+                info.id,
+                info.major,
+                info.minor,
+                info.bugfix,
+                info.fwid
+            ),
+            None => defmt::warn!("SoftDevice info structure not found!"),
+        }
+
+        Ok(())
+    }
+
+    fn add_device_info_service(&mut self) -> Result<(), SdError> {
+        let service = Service::new(Uuid::Sig(DEVICE_INFO_SERVICE_UUID))?;
+
+        let mut firmware_rev: String<32> = String::new();
+        // Can't fail, the version and hash are far shorter
+        write!(firmware_rev, "{}-{}", FIRMWARE_VERSION, GIT_HASH).ok();
+        service
+            .characteristic(Uuid::Sig(FIRMWARE_REV_CHARAC_UUID))
+            .read()
+            .value(firmware_rev.as_bytes())
+            .register()?;
+
+        service
+            .characteristic(Uuid::Sig(HARDWARE_REV_CHARAC_UUID))
+            .read()
+            .value(HARDWARE_REVISION.as_bytes())
+            .register()?;
+
+        let mut software_rev: String<32> = String::new();
+        if let Some(info) = SoftDeviceInfo::read() {
+            write!(
+                software_rev,
+                "S{} {}.{}.{} FWID 0x{:04X}",
+                info.id, info.major, info.minor, info.bugfix, info.fwid
+            )
+            .ok();
+        } else {
+            software_rev.push_str("unknown").ok();
+        }
+        service
+            .characteristic(Uuid::Sig(SOFTWARE_REV_CHARAC_UUID))
+            .read()
+            .value(software_rev.as_bytes())
+            .register()?;
+        Ok(())
+    }
+
+    /// Changes the advertising intervals, applied when advertising is
+    /// (re)started the next time.
+    pub fn set_adv_schedule(&mut self, schedule: AdvSchedule) {
+        self.adv_schedule = schedule;
+    }
+
+    /// Sets the device name, an empty one selects the default derived from
+    /// the address. Advertising picks it up when it's (re)started.
+    pub fn set_device_name(&mut self, name: &str) -> Result<(), SdError> {
+        self.device_name.clear();
+        if self.device_name.push_str(name).is_err() {
+            return Err(SdError::InvalidLength);
+        }
+        if !self.rover_io.is_registered() {
+            // init() applies it
+            return Ok(());
+        }
+        self.apply_device_name()
+    }
+
+    fn effective_device_name(&self) -> String<MAX_NAME_LEN> {
+        if self.device_name.is_empty() {
+            default_device_name(&self.addr)
+        } else {
+            self.device_name.clone()
+        }
+    }
+
+    fn apply_device_name(&self) -> Result<(), SdError> {
+        let name = self.effective_device_name();
+        SdError::check(unsafe {
+            sd::sd_ble_gap_device_name_set(
+                &self.rover_io_security.raw(),
+                name.as_ptr(),
+                name.len() as u16,
+            )
+        })
+    }
+
+    /// Reads back the name the central wrote to the GAP device name
+    /// characteristic.
+    fn on_device_name_written(&mut self) {
+        let mut buf = [0u8; MAX_NAME_LEN];
+        let mut len = buf.len() as u16;
+        if let Err(e) =
+            SdError::check(unsafe { sd::sd_ble_gap_device_name_get(buf.as_mut_ptr(), &mut len) })
+        {
+            defmt::error!("sd_ble_gap_device_name_get() failed: {}", e);
+            return;
+        }
+        match core::str::from_utf8(&buf[..len as usize]) {
+            Ok(name) => {
+                defmt::info!("Device name changed to {=str}", name);
+                self.device_name.clear();
+                // Can't fail, the SoftDevice limits the length
+                self.device_name.push_str(name).ok();
+                (self.device_name_cb)(self.device_name.clone());
             }
+            Err(_) => defmt::warn!("Device name is not valid UTF-8, not storing it."),
         }
+    }
+
+    /// Sets the power failure threshold, one of `NRF_POWER_THRESHOLD_*`.
+    /// Has to be called before `init()`.
+    pub fn set_pof_threshold(&mut self, threshold: u8) {
+        self.pof_threshold = threshold;
+    }
 
-        let mut adv_handle = sd::BLE_GAP_ADV_SET_HANDLE_NOT_SET as u8;
+    /// Sets the security required to write the rover and configuration
+    /// characteristics, `EncryptedMitm` by default. Has to be called before
+    /// `init()`.
+    pub fn set_rover_io_security(&mut self, mode: SecurityMode) {
+        self.rover_io_security = mode;
+    }
+
+    /// Changes how future MTU exchange and PHY update requests are
+    /// answered.
+    pub fn set_link_preferences(&mut self, preferences: LinkPreferences) {
+        self.link_preferences = preferences;
+    }
 
+    fn configure_advertising(&mut self) -> Result<(), SdError> {
+        let (interval_ms, duration_s) = match self.adv_mode {
+            AdvMode::Fast => (
+                self.adv_schedule.fast_interval_ms,
+                self.adv_schedule.fast_duration_s,
+            ),
+            AdvMode::Slow => (self.adv_schedule.slow_interval_ms, 0),
+        };
+        let name = self.effective_device_name();
+        // Only modified while not advertising
+        unsafe {
+            ADV_DATA = AdvertisingData::new()
+                .flags(FLAG_LE_GENERAL_DISCOVERABLE | FLAG_BR_EDR_NOT_SUPPORTED)
+                .complete_name(&name);
+        }
         let adv_params = sd::ble_gap_adv_params_t {
             properties: sd::ble_gap_adv_properties_t {
                 // Undirected means non-paired in BLE speak
@@ -179,10 +814,12 @@ impl SoftDevice {
                 // See https://infocenter.nordicsemi.com/index.jsp?topic=%2Fcom.nordic.infocenter.s132.api.v7.3.0%2Fstructble__gap__adv__properties__t.html
                 _bitfield_1: sd::ble_gap_adv_properties_t::new_bitfield_1(0, 0),
             },
-            p_peer_addr: core::ptr::null(), // as in NordicBlinky, was &peer_addr
-            interval: 64, // as in NordicBlinky, was 480,                                // 300ms / 625µs = 480
-            duration: sd::BLE_GAP_ADV_TIMEOUT_GENERAL_UNLIMITED as u16, // as in NordicBlinky, was 10000,                              // 100s / 10ms = 10000
-            max_adv_evts: 0,                                            // no limit
+            p_peer_addr: core::ptr::null(),
+            // In units of 0.625 ms
+            interval: interval_ms as u32 * 8 / 5,
+            // In units of 10 ms, 0 = BLE_GAP_ADV_TIMEOUT_GENERAL_UNLIMITED
+            duration: (duration_s as u32 * 100).min(u16::MAX as u32) as u16,
+            max_adv_evts: 0, // no limit
             // mask is inverted (for my logic): a 0 enabled the channel, a 1 disables it. Enable all channels:
             channel_mask: [0x00, 0x00, 0x00, 0x00, 0x00],
             filter_policy: sd::BLE_GAP_ADV_FP_ANY as u8,
@@ -192,342 +829,391 @@ impl SoftDevice {
             // scan_req_notification: Raise GAP event when scanned
             _bitfield_1: sd::ble_gap_adv_params_t::new_bitfield_1(0, 1),
         };
-
-        if unsafe { sd::sd_ble_uuid_vs_add(&BASE_UUID, &mut self.base_uuid_type) }
-            != sd::NRF_SUCCESS
-        {
-            defmt::error!("sd_ble_uuid_vs_add() failed!");
-            return false;
-        }
-
-        let uuid = sd::ble_uuid_t {
-            type_: self.base_uuid_type,
-            uuid: ROVER_SERVICE_UUID,
-        };
-
-        if unsafe {
-            sd::sd_ble_gatts_service_add(
-                sd::BLE_GATTS_SRVC_TYPE_PRIMARY as u8,
-                &uuid,
-                &mut self.rover_service_handle,
-            )
-        } != sd::NRF_SUCCESS
-        {
-            defmt::error!("sd_ble_uuid_vs_add() failed!");
-            return false;
-        }
-
-        let uuid = sd::ble_uuid_t {
-            type_: self.base_uuid_type,
-            uuid: ROVER_CHARAC_UUID,
-        };
-        let attr_md = sd::ble_gatts_attr_md_t {
-            read_perm: sd::ble_gap_conn_sec_mode_t {
-                _bitfield_1: sd::ble_gap_conn_sec_mode_t::new_bitfield_1(1, 1),
-            },
-            write_perm: sd::ble_gap_conn_sec_mode_t {
-                _bitfield_1: sd::ble_gap_conn_sec_mode_t::new_bitfield_1(1, 1),
-            },
-            _bitfield_1: sd::ble_gatts_attr_md_t::new_bitfield_1(
-                0,
-                sd::BLE_GATTS_VLOC_STACK as u8,
-                0,
-                0,
-            ),
-        };
-
-        let charac_meta = sd::ble_gatts_char_md_t {
-            char_props: sd::ble_gatt_char_props_t {
-                _bitfield_1: sd::ble_gatt_char_props_t::new_bitfield_1(0, 0, 0, 1, 0, 0, 0),
-            },
-            char_ext_props: sd::ble_gatt_char_ext_props_t {
-                _bitfield_1: sd::ble_gatt_char_ext_props_t::new_bitfield_1(1, 0),
-            },
-            p_char_user_desc: &CHARAC_DESC[0],
-            char_user_desc_max_size: CHARAC_DESC.len() as u16,
-            char_user_desc_size: CHARAC_DESC.len() as u16,
-            p_char_pf: core::ptr::null(),
-            p_user_desc_md: core::ptr::null(),
-            p_cccd_md: core::ptr::null(),
-            p_sccd_md: core::ptr::null(),
-        };
-
-        let mut val = [0i8, 0i8];
-        let buf = &mut val[0] as *mut i8 as *mut u8;
-
-        let charac_value = sd::ble_gatts_attr_t {
-            p_uuid: &uuid,
-            p_attr_md: &attr_md,
-            init_len: 2,
-            init_offs: 0,
-            max_len: 2,
-            p_value: buf,
-        };
-
-        if unsafe {
-            sd::sd_ble_gatts_characteristic_add(
-                self.rover_service_handle,
-                &charac_meta,
-                &charac_value,
-                &mut self.charac_handle,
-            )
-        } != sd::NRF_SUCCESS
-        {
-            defmt::error!("Failed to add characteristic!");
-            return false;
-        }
-
-        let mut config_ok = false;
-        match unsafe {
+        SdError::check(unsafe {
             let adv_data_handle: sd::ble_gap_adv_data_t = sd::ble_gap_adv_data_t {
                 adv_data: sd::ble_data_t {
-                    p_data: &mut ADV_DATA[0],
+                    p_data: ADV_DATA.as_mut_ptr(),
                     len: ADV_DATA.len() as u16,
                 },
                 scan_rsp_data: sd::ble_data_t {
-                    p_data: &mut SCAN_RESP[0],
+                    p_data: SCAN_RESP.as_mut_ptr(),
                     len: SCAN_RESP.len() as u16,
                 },
             };
-            defmt::debug!(
-                "adv buffer:       0x{:08x}",
-                adv_data_handle.adv_data.p_data as u32
-            );
-            defmt::debug!(
-                "scan resp buffer: 0x{:08x}",
-                adv_data_handle.scan_rsp_data.p_data as u32
-            );
-            sd::sd_ble_gap_adv_set_configure(&mut adv_handle, &adv_data_handle, &adv_params)
-        } {
-            sd::NRF_SUCCESS => {
-                defmt::debug!("Advertisement config successful!");
-                config_ok = true
-            }
-            sd::NRF_ERROR_INVALID_LENGTH => {
-                defmt::error!("Advertisement config failed: NRF_ERROR_INVALID_LENGTH")
-            }
-            sd::NRF_ERROR_NOT_SUPPORTED => {
-                defmt::error!("Advertisement config failed: NRF_ERROR_NOT_SUPPORTED")
-            }
-            sd::NRF_ERROR_NO_MEM => {
-                defmt::error!("Advertisement config failed: NRF_ERROR_NO_MEM")
-            }
-            sd::BLE_ERROR_GAP_UUID_LIST_MISMATCH => {
-                defmt::error!("Advertisement config fail0x00,ed: BLE_ERROR_GAP_UUID_LIST_MISMATCH")
-            }
-            sd::NRF_ERROR_INVALID_ADDR => {
-                defmt::error!("Advertisement config failed: NRF_ERROR_INVALID_ADDR")
-            }
-            sd::NRF_ERROR_INVALID_PARAM => {
-                defmt::error!("Advertisement config failed: NRF_ERROR_INVALID_PARAM")
-            }
-            sd::BLE_ERROR_GAP_INVALID_BLE_ADDR => {
-                defmt::error!("Advertisement config failed: BLE_ERROR_GAP_INVALID_BLE_ADDR")
-            }
-            sd::NRF_ERROR_INVALID_STATE => {
-                defmt::error!("Advertisement config failed: NRF_ERROR_INVALID_STATE")
-            }
-            sd::BLE_ERROR_GAP_DISCOVERABLE_WITH_WHITELIST => {
-                defmt::error!(
-                    "Advertisement config failed: BLE_ERROR_GAP_DISCOVERABLE_WITH_WHITELIST"
-                )
-            }
-            sd::BLE_ERROR_INVALID_ADV_HANDLE => {
-                defmt::error!("Advertisement config failed: BLE_ERROR_INVALID_ADV_HANDLE")
-            }
-            sd::NRF_ERROR_INVALID_FLAGS => {
-                defmt::error!("Advertisement config failed: NRF_ERROR_INVALID_FLAGS")
-            }
-            sd::NRF_ERROR_INVALID_DATA => {
-                defmt::error!("Advertisement config failed: NRF_ERROR_INVALID_DATA")
-            }
-            other => defmt::error!("Advertisement config failed: {}", other),
+            // Assigns adv_handle on the first call, reconfigures the same
+            // advertising set afterwards
+            sd::sd_ble_gap_adv_set_configure(&mut self.adv_handle, &adv_data_handle, &adv_params)
+        })
+    }
+
+    fn start_advertising(&mut self) -> Result<(), SdError> {
+        SdError::check(unsafe { sd::sd_ble_gap_adv_start(self.adv_handle, CONN_CFG_TAG) })
+    }
+
+    /// Starts advertising again after a disconnect or timeout.
+    fn restart_advertising(&mut self, mode: AdvMode) {
+        self.adv_mode = mode;
+        let result = self
+            .configure_advertising()
+            .and_then(|()| self.start_advertising());
+        match result {
+            Ok(()) => defmt::debug!("Advertising restarted ({}).", mode),
+            Err(e) => defmt::error!("Failed to restart advertising: {}", e),
         }
-        if !config_ok {
-            return false;
+    }
+
+    /// Disables the SoftDevice again, e.g. to retry after `init()` failed.
+    pub fn disable(&mut self) {
+        if let Err(e) = SdError::check(unsafe { sd::sd_softdevice_disable() }) {
+            defmt::error!("Failed to disable SoftDevice: {}", e);
         }
+        self.base_uuid_type = 0xff;
+        self.adv_handle = sd::BLE_GAP_ADV_SET_HANDLE_NOT_SET as u8;
+        self.rover_io = Characteristic::default();
+        self.telemetry = Characteristic::default();
+        self.config = Characteristic::default();
+        self.faults = Characteristic::default();
+        self.battery_level = Characteristic::default();
+    }
 
-        if unsafe { sd::sd_ble_gap_adv_start(adv_handle, sd::BLE_CONN_CFG_TAG_DEFAULT as u8) }
-            == sd::NRF_SUCCESS
-        {
-            defmt::debug!("Advertisement started successfully!")
-        } else {
-            defmt::error!("Error starting advertisement!")
+    /// Sends the telemetry to the central if it enabled notifications.
+    /// Otherwise, only the characteristic value is updated for reads.
+    pub fn notify_telemetry(&mut self, telemetry: &Telemetry) -> Result<(), SdError> {
+        let value = telemetry.encode();
+        self.notify(self.telemetry, &value)
+    }
+
+    /// Sends the reply to a configuration command.
+    pub fn send_config_reply(&mut self, reply: &config::Reply) -> Result<(), SdError> {
+        self.notify(self.config, reply.as_bytes())
+    }
+
+    /// Makes the newest faults of the log readable, newest first, encoded
+    /// as described for `Fault::encode()`.
+    pub fn set_faults(&mut self, log: &FaultLog) -> Result<(), SdError> {
+        if !self.faults.is_registered() {
+            // init() didn't succeed (yet)
+            return Ok(());
         }
+        let mut value = [0u8; MAX_FAULTS_READABLE * Fault::LEN];
+        let mut len = 0;
+        for (fault, record) in log.iter().rev().zip(value.chunks_mut(Fault::LEN)) {
+            record.copy_from_slice(&fault.encode());
+            len += Fault::LEN;
+        }
+        self.faults.set_value(&value[..len])
+    }
 
-        true
+    /// Updates the Battery Service's level, `percent` is 0..=100. The
+    /// central is notified on change.
+    pub fn set_battery_level(&mut self, percent: u8) -> Result<(), SdError> {
+        if percent == self.battery_level_value {
+            return Ok(());
+        }
+        self.battery_level_value = percent;
+        self.notify(self.battery_level, &[percent])
     }
 
-    pub fn handle_evt_notify(&self) {
-        let mut evt: Aligned<A4, sd::ble_evt_t> = Aligned(sd::ble_evt_t {
-            header: sd::ble_evt_hdr_t {
-                evt_id: 0,
-                evt_len: 0,
-            },
-            evt: sd::ble_evt_t__bindgen_ty_1 {
-                common_evt: Default::default(),
-                gap_evt: Default::default(),
-                gattc_evt: Default::default(),
-                gatts_evt: Default::default(),
-                bindgen_union_field: Default::default(),
-            },
-        });
+    fn notify(&mut self, charac: Characteristic, value: &[u8]) -> Result<(), SdError> {
+        if !charac.is_registered() {
+            // init() didn't succeed (yet)
+            return Ok(());
+        }
+        // If the queue is full, the value is only updated and the central
+        // gets the next one
+        let conn = self.connection.filter(|_| self.hvn_tx_free > 0);
+        if let Some(conn) = conn {
+            match charac.notify(conn.handle, value) {
+                Ok(()) => {
+                    self.hvn_tx_free -= 1;
+                    return Ok(());
+                }
+                // Notifications are not enabled in the CCCD
+                Err(SdError::InvalidState) | Err(SdError::GattsSysAttrMissing) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        charac.set_value(value)
+    }
+
+    /// Drains the BLE event queue. Does nothing while the SoftDevice is
+    /// disabled, so it's fine to call periodically. Returns false if an
+    /// event was left in the queue.
+    pub fn handle_evt_notify(&mut self) -> bool {
+        if !is_enabled() {
+            return true;
+        }
+        let mut evt_buf: Aligned<A4, [u8; EVT_BUF_LEN]> = Aligned([0; EVT_BUF_LEN]);
         debug_assert!(sd::BLE_EVT_PTR_ALIGNMENT <= 4);
-        // * dereferences Aligned<ble_evt_t> to get ble_evt_t
-        let evt_buf = &mut *evt as *mut sd::ble_evt_t as *mut u8;
-        let mut buf_len: u16 = core::mem::size_of::<sd::ble_evt_t>() as u16;
         loop {
-            match unsafe { sd::sd_ble_evt_get(evt_buf, &mut buf_len) } {
+            // In: buffer size, out: event length
+            let mut buf_len = EVT_BUF_LEN as u16;
+            match unsafe { sd::sd_ble_evt_get(evt_buf.as_mut_ptr(), &mut buf_len) } {
                 sd::NRF_SUCCESS => {
-                    // * dereferences Aligned<ble_evt_t> to get ble_evt_t
-                    self.dispatch_event(&*evt);
+                    let evt = unsafe { &*(evt_buf.as_ptr() as *const sd::ble_evt_t) };
+                    self.handle_event(BleEvent::decode(evt));
                 }
                 sd::NRF_ERROR_INVALID_ADDR => defmt::error!("sd_ble_evt_get: Invalid address!"),
                 sd::NRF_ERROR_NOT_FOUND => {
                     // Queue is empty, no more events to process
-                    break;
+                    return true;
+                }
+                sd::NRF_ERROR_DATA_SIZE => {
+                    // The event stays in the queue, don't spin on it
+                    defmt::error!("sd_ble_evt_get: Buffer too small!");
+                    return false;
                 }
-                sd::NRF_ERROR_DATA_SIZE => defmt::error!("sd_ble_evt_get: Buffer too small!"),
                 _ => defmt::error!("sd_ble_evt_get: Invalid return value!"),
             }
         }
     }
 
-    fn dispatch_event(&self, evt: &sd::ble_evt_t) {
-        let evt_id = evt.header.evt_id as u32;
-        match evt_id {
-            sd::BLE_EVT_BASE..=sd::BLE_EVT_LAST => {
-                let common_evt = unsafe { evt.evt.common_evt.as_ref() };
-                self.handle_common_evt(evt_id, common_evt);
-            }
-            sd::BLE_GAP_EVT_BASE..=sd::BLE_GAP_EVT_LAST => {
-                let gap_evt = unsafe { evt.evt.gap_evt.as_ref() };
-                self.handle_gap_evt(evt_id, gap_evt);
-            }
-            sd::BLE_GATTC_EVT_BASE..=sd::BLE_GATTC_EVT_LAST => {
-                let gattc_evt = unsafe { evt.evt.gattc_evt.as_ref() };
-                self.handle_gattc_evt(evt_id, gattc_evt);
-            }
-            sd::BLE_GATTS_EVT_BASE..=sd::BLE_GATTS_EVT_LAST => {
-                let gatts_evt = unsafe { evt.evt.gatts_evt.as_ref() };
-                self.handle_gatts_evt(evt_id, gatts_evt);
-            }
-            _ => defmt::error!("dispatch_event: Invalid event ID: {}", evt_id),
-        }
-    }
-
-    fn handle_common_evt(&self, evt_id: u32, _evt: &sd::ble_common_evt_t) {
-        match evt_id {
-            sd::BLE_COMMON_EVTS_BLE_EVT_USER_MEM_REQUEST => {
-                defmt::error!("Common event: Memory request not handled!")
+    fn handle_event(&mut self, event: BleEvent) {
+        match event {
+            BleEvent::Connected {
+                conn_handle,
+                peer,
+                role,
+                params,
+            } => {
+                let connection = Connection::new(conn_handle, peer, role, params);
+                defmt::info!("Connected: {}", connection);
+                self.connection = Some(connection);
+                self.hvn_tx_free = HVN_TX_QUEUE_SIZE;
             }
-            sd::BLE_COMMON_EVTS_BLE_EVT_USER_MEM_RELEASE => {
-                defmt::error!("Common event: Memory release not handled!")
-            }
-            _ => defmt::error!("Common event: Invalid event ID: {}!", evt_id),
-        }
-    }
-    fn handle_gap_evt(&self, evt_id: u32, _evt: &sd::ble_gap_evt_t) {
-        match evt_id {
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_ADV_SET_TERMINATED => {
-                defmt::debug!("GAP event: Advertising set terminated.")
-            }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_AUTH_KEY_REQUEST => {
-                defmt::debug!("GAP event: Authentication key request.")
-            }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_AUTH_STATUS => {
-                defmt::debug!("GAP event: Authentication completed.")
+            BleEvent::Disconnected { reason, .. } => {
+                defmt::info!("Disconnected, reason 0x{:02x}.", reason);
+                self.connection = None;
+                (self.disconnect_cb)();
+                // Not possible while connected, a new bond may be pending
+                if let Err(e) = self.security.update_identities() {
+                    defmt::error!("Failed to set device identities: {}", e);
+                }
+                self.restart_advertising(AdvMode::Fast);
             }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_CONNECTED => defmt::info!("GAP event: Connected."),
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_CONN_PARAM_UPDATE => {
-                defmt::debug!("GAP event: Connection parameters updated.")
+            BleEvent::ConnParamUpdate { params, .. } => {
+                defmt::debug!("Connection parameters updated: {}", params);
+                if let Some(conn) = self.connection.as_mut() {
+                    conn.params = params;
+                }
             }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_CONN_SEC_UPDATE => {
-                defmt::debug!("GAP event: Connection security updated.")
+            BleEvent::PhyUpdate {
+                status,
+                tx_phy,
+                rx_phy,
+                ..
+            } => {
+                defmt::debug!(
+                    "PHY update: status {}, TX {}, RX {}",
+                    status,
+                    tx_phy,
+                    rx_phy
+                );
+                if status as u32 == sd::BLE_HCI_STATUS_CODE_SUCCESS {
+                    if let Some(conn) = self.connection.as_mut() {
+                        conn.tx_phy = tx_phy;
+                        conn.rx_phy = rx_phy;
+                    }
+                }
             }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => defmt::info!("GAP event: Disconnected."),
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_KEY_PRESSED => defmt::debug!("GAP event: Key pressed."),
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_PASSKEY_DISPLAY => {
-                defmt::debug!("GAP event: Passkey display request.")
+            BleEvent::MtuRequest {
+                conn_handle,
+                client_mtu,
+            } => {
+                let server_mtu = self
+                    .link_preferences
+                    .att_mtu
+                    .clamp(sd::BLE_GATT_ATT_MTU_DEFAULT as u16, MAX_ATT_MTU);
+                match SdError::check(unsafe {
+                    sd::sd_ble_gatts_exchange_mtu_reply(conn_handle, server_mtu)
+                }) {
+                    Ok(()) => {
+                        // Both sides use the smaller one
+                        let att_mtu = client_mtu
+                            .min(server_mtu)
+                            .max(sd::BLE_GATT_ATT_MTU_DEFAULT as u16);
+                        defmt::debug!("ATT MTU: {}", att_mtu);
+                        if let Some(conn) = self.connection.as_mut() {
+                            conn.att_mtu = att_mtu;
+                        }
+                    }
+                    Err(e) => defmt::error!("sd_ble_gatts_exchange_mtu_reply() failed: {}", e),
+                }
             }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_PHY_UPDATE => {
-                defmt::debug!("GAP event: PHY update completed.")
+            BleEvent::PhyRequest {
+                conn_handle,
+                tx_phys,
+                rx_phys,
+            } => {
+                defmt::debug!(
+                    "PHY update request: TX 0x{:02x}, RX 0x{:02x}",
+                    tx_phys,
+                    rx_phys
+                );
+                let phys = sd::ble_gap_phys_t {
+                    tx_phys: self.link_preferences.phys,
+                    rx_phys: self.link_preferences.phys,
+                };
+                if let Err(e) =
+                    SdError::check(unsafe { sd::sd_ble_gap_phy_update(conn_handle, &phys) })
+                {
+                    defmt::error!("sd_ble_gap_phy_update() failed: {}", e);
+                }
             }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_PHY_UPDATE_REQUEST => {
-                defmt::debug!("GAP event: PHY update request.")
+            BleEvent::DataLengthRequest { conn_handle, .. } => {
+                // NULL parameters let the SoftDevice pick the largest data
+                // length its configuration allows
+                if let Err(e) = SdError::check(unsafe {
+                    sd::sd_ble_gap_data_length_update(
+                        conn_handle,
+                        core::ptr::null(),
+                        core::ptr::null_mut(),
+                    )
+                }) {
+                    defmt::error!("sd_ble_gap_data_length_update() failed: {}", e);
+                }
             }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_RSSI_CHANGED => defmt::debug!("GAP event: RSSI report."),
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_SCAN_REQ_REPORT => {
-                //defmt::debug!("GAP event: Scan request report.")
+            BleEvent::AdvSetTerminated { .. } => {
+                defmt::debug!("Advertising set terminated.");
+                // Fast advertising timed out, continue slowly until someone
+                // connects
+                self.restart_advertising(AdvMode::Slow);
             }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_SEC_INFO_REQUEST => {
-                defmt::debug!("GAP event: Security information request.")
+            BleEvent::HvnTxComplete { count, .. } => {
+                self.hvn_tx_free = (self.hvn_tx_free + count).min(HVN_TX_QUEUE_SIZE);
             }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_SEC_PARAMS_REQUEST => {
-                defmt::debug!("GAP event: Security parameter request.")
+            BleEvent::SysAttrMissing { conn_handle } => {
+                defmt::debug!("Pending access to persistent system attribute.");
+                // No stored CCCD values, start out with notifications disabled
+                if let Err(e) = SdError::check(unsafe {
+                    sd::sd_ble_gatts_sys_attr_set(conn_handle, core::ptr::null(), 0, 0)
+                }) {
+                    defmt::error!("sd_ble_gatts_sys_attr_set() failed: {}", e);
+                }
             }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_SEC_REQUEST => {
-                defmt::debug!("GAP event: Security request.")
+            BleEvent::Write {
+                uuid: Uuid::Sig(uuid),
+                ..
+            } if uuid as u32 == sd::BLE_UUID_GAP_CHARACTERISTIC_DEVICE_NAME => {
+                self.on_device_name_written();
             }
-            sd::BLE_GAP_EVTS_BLE_GAP_EVT_TIMEOUT => defmt::debug!("GAP event: Timeout."),
-            _ => defmt::error!("GAP event: Invalid event ID: {}!", evt_id),
-        }
-    }
-    fn handle_gatts_evt(&self, evt_id: u32, _evt: &sd::ble_gatts_evt_t) {
-        match evt_id {
-            sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_EXCHANGE_MTU_REQUEST => {
-                defmt::debug!("GATTS event: MTU exchange request.")
+            BleEvent::Write { handle, data, .. } => {
+                if handle == self.rover_io.value_handle && data.len() == 2 {
+                    (self.speed_update_cb)(data[0] as i8, data[1] as i8);
+                } else if handle == self.config.value_handle {
+                    let reply = match Command::parse(data) {
+                        Ok(command) => {
+                            let opcode = command.opcode() as u8;
+                            if (self.config_cb)(command) {
+                                None
+                            } else {
+                                Some(config::Reply::new(opcode, config::Status::Busy))
+                            }
+                        }
+                        Err(reply) => Some(reply),
+                    };
+                    if let Some(reply) = reply {
+                        if let Err(e) = self.send_config_reply(&reply) {
+                            defmt::error!("Failed to send config reply: {}", e);
+                        }
+                    }
+                } else {
+                    defmt::debug!("Write to handle {}: {:02x}", handle, data);
+                }
             }
-            sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_HVC => {
-                defmt::debug!("GATTS event: Handle value confirmation.")
+            BleEvent::SecParamsRequest { conn_handle } => {
+                if let Err(e) = self.security.on_sec_params_request(conn_handle) {
+                    defmt::error!("sd_ble_gap_sec_params_reply() failed: {}", e);
+                }
             }
-            sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_HVN_TX_COMPLETE => {
-                defmt::debug!("GATTS event: Handle value notification completed.")
+            BleEvent::LescDhkeyRequest { conn_handle } => {
+                if let Err(e) = self.security.on_lesc_dhkey_request(conn_handle) {
+                    defmt::error!("sd_ble_gap_lesc_dhkey_reply() failed: {}", e);
+                }
             }
-            sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_RW_AUTHORIZE_REQUEST => {
-                defmt::debug!("GATTS event: RW authorization request.")
+            BleEvent::PasskeyDisplay { passkey, .. } => {
+                self.security.on_passkey_display(&passkey);
             }
-            sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_SC_CONFIRM => {
-                defmt::debug!("GATTS event: Service change confirmation.")
+            BleEvent::AuthStatus {
+                status,
+                bonded,
+                lesc,
+                ..
+            } => {
+                let success = status as u32 == sd::BLE_GAP_SEC_STATUS_SUCCESS;
+                defmt::info!(
+                    "Pairing status 0x{:02x}, bonded: {}, LESC: {}",
+                    status,
+                    bonded,
+                    lesc
+                );
+                if let Some(conn) = self.connection {
+                    if let Err(e) = self.security.on_auth_status(success, bonded, &conn.peer) {
+                        defmt::error!("Failed to store bond: {}", e);
+                    }
+                }
             }
-            sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_SYS_ATTR_MISSING => {
-                defmt::debug!("GATTS event: Pending access to persistent system attribute.")
+            BleEvent::SecInfoRequest {
+                conn_handle,
+                peer,
+                ediv,
+                rand,
+            } => {
+                if let Err(e) = self
+                    .security
+                    .on_sec_info_request(conn_handle, &peer, ediv, &rand)
+                {
+                    defmt::error!("sd_ble_gap_sec_info_reply() failed: {}", e);
+                }
             }
-            sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_TIMEOUT => {
-                defmt::error!("GATTS event: Response timeout.")
+            BleEvent::ConnSecUpdate {
+                sec_mode,
+                level,
+                key_size,
+                ..
+            } => {
+                defmt::info!(
+                    "Link security: mode {}, level {}, key size {}",
+                    sec_mode,
+                    level,
+                    key_size
+                );
+                if let Some(conn) = self.connection.as_mut() {
+                    conn.security_level = level;
+                }
             }
-            sd::BLE_GATTS_EVTS_BLE_GATTS_EVT_WRITE => {
-                defmt::debug!("GATTS event: Write operation performed.");
-                let (speed_r, speed_l) = self.get_speed().unwrap();
-                (self.speed_update_cb)(speed_r, speed_l);
+            BleEvent::UserMemRequest { .. } | BleEvent::UserMemRelease { .. } => {
+                defmt::error!("User memory event not handled: {}", event)
             }
-            _ => defmt::error!("GATTS event: Invalid event ID: {}!", evt_id),
+            // Nothing to do, the SoftDevice uses its default reply or the
+            // event is informational only
+            BleEvent::ScanReqReport => (),
+            _ => defmt::debug!("BLE event: {}", event),
         }
     }
-    fn handle_gattc_evt(&self, evt_id: u32, _evt: &sd::ble_gattc_evt_t) {
-        defmt::error!(
-            "GATTC event handling not implemented! Ignoring ID: {}",
-            evt_id
-        );
+
+    /// Bonding state, e.g. to forget all bonds.
+    pub fn security(&mut self) -> &mut Security {
+        &mut self.security
     }
 
-    pub fn get_speed(&self) -> Option<(i8, i8)> {
-        let mut val = [0i8, 0i8];
-        let buf = &mut val[0] as *mut i8 as *mut u8;
+    /// The current connection to the central, if any.
+    pub fn connection(&self) -> Option<&Connection> {
+        self.connection.as_ref()
+    }
 
-        let mut gatts_val = sd::ble_gatts_value_t {
-            len: 2,
-            offset: 0,
-            p_value: buf,
+    pub fn get_speed(&self) -> Option<(i8, i8)> {
+        let conn_handle = match self.connection {
+            Some(conn) => conn.handle,
+            None => sd::BLE_CONN_HANDLE_INVALID as u16,
         };
-
-        if unsafe { sd::sd_ble_gatts_value_get(0, self.charac_handle.value_handle, &mut gatts_val) }
-            != sd::NRF_SUCCESS
-        {
-            defmt::error!("sd_ble_gatts_value_get() failed!");
-            None
-        } else {
-            Some((val[0], val[1]))
+        let mut val = [0u8; 2];
+        match self.rover_io.value(conn_handle, &mut val) {
+            Ok(_) => Some((val[0] as i8, val[1] as i8)),
+            Err(e) => {
+                defmt::error!("sd_ble_gatts_value_get() failed: {}", e);
+                None
+            }
         }
     }
 }
@@ -0,0 +1,84 @@
+//! Status report periodically sent to the central via notifications.
+
+/// Conditions the central should know about, see `Telemetry::faults`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Faults(u16);
+
+impl Faults {
+    /// Motors were stopped as no speed value arrived in time
+    pub const FAILSAFE: Faults = Faults(1 << 0);
+    /// Battery state of charge is below the warning threshold
+    pub const LOW_BATTERY: Faults = Faults(1 << 1);
+    /// Supply voltage dropped below the power failure threshold, the
+    /// motors are off until the battery recovered
+    pub const POWER_FAILURE: Faults = Faults(1 << 2);
+    /// Booted in safe mode after repeated fault resets, speed values are
+    /// ignored until the next power-up or pin reset
+    pub const SAFE_MODE: Faults = Faults(1 << 3);
+
+    pub const fn empty() -> Faults {
+        Faults(0)
+    }
+
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(self, other: Faults) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Faults) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Faults) {
+        self.0 &= !other.0;
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Telemetry {
+    /// Applied PWM duty, `0..=max_duty` of the PWM peripheral
+    pub duty_left: u16,
+    pub duty_right: u16,
+    pub reverse_left: bool,
+    pub reverse_right: bool,
+    pub standby: bool,
+    pub uptime_s: u32,
+    /// 0 if not measured
+    pub battery_mv: u16,
+    pub faults: Faults,
+}
+
+impl Telemetry {
+    /// Incremented whenever the encoding changes
+    pub const VERSION: u8 = 1;
+    pub const LEN: usize = 14;
+
+    /// Encodes the telemetry for the characteristic value:
+    ///
+    /// | Offset | Size | Content                                           |
+    /// |--------|------|---------------------------------------------------|
+    /// | 0      | 1    | `VERSION`                                         |
+    /// | 1      | 2    | Duty left                                         |
+    /// | 3      | 2    | Duty right                                        |
+    /// | 5      | 1    | Bit 0: left reverse, 1: right reverse, 2: standby |
+    /// | 6      | 4    | Uptime in seconds                                 |
+    /// | 10     | 2    | Battery voltage in mV                             |
+    /// | 12     | 2    | Fault bits                                        |
+    ///
+    /// All values are little endian.
+    pub fn encode(&self) -> [u8; Telemetry::LEN] {
+        let mut buf = [0u8; Telemetry::LEN];
+        buf[0] = Telemetry::VERSION;
+        buf[1..3].copy_from_slice(&self.duty_left.to_le_bytes());
+        buf[3..5].copy_from_slice(&self.duty_right.to_le_bytes());
+        buf[5] =
+            self.reverse_left as u8 | (self.reverse_right as u8) << 1 | (self.standby as u8) << 2;
+        buf[6..10].copy_from_slice(&self.uptime_s.to_le_bytes());
+        buf[10..12].copy_from_slice(&self.battery_mv.to_le_bytes());
+        buf[12..14].copy_from_slice(&self.faults.bits().to_le_bytes());
+        buf
+    }
+}
@@ -7,10 +7,487 @@ use rusty_rover as _; // memory layout + panic handler
 // feature)
 #[defmt_test::tests]
 mod tests {
-    use defmt::assert;
+    use core::convert::Infallible;
+    use defmt::{assert, assert_eq};
+    use embedded_hal::digital::v2::OutputPin;
+    use rusty_rover::adv::{
+        AdvertisingData, FLAG_BR_EDR_NOT_SUPPORTED, FLAG_LE_GENERAL_DISCOVERABLE,
+    };
+    use rusty_rover::battery::{BatteryConfig, LI_ION_CURVE};
+    use rusty_rover::bond::{self, Bond, BondStore, MAX_BONDS};
+    use rusty_rover::config::{Command, Opcode, Reply, Status};
+    use rusty_rover::connection::ConnParams;
+    use rusty_rover::crash;
+    use rusty_rover::fault_log::{self, Fault, FaultKind, FaultLog, MAX_FAULTS, TEXT_LEN};
+    use rusty_rover::flash::{self, PAGE_HEADER_LEN};
+    use rusty_rover::gatt::{uuid128, CharacteristicBuilder, SecurityMode, Uuid};
+    use rusty_rover::monotonic;
+    use rusty_rover::motor::{DualMotorDriver, Motor, MotorPwm};
+    use rusty_rover::ramp::{Ramp, RampLimits};
+    use rusty_rover::settings::{
+        self, default_device_name, encode_record, Key, Settings, SettingsError, MAX_RECORD_WORDS,
+    };
+    use rusty_rover::telemetry::{Faults, Telemetry};
+
+    #[derive(Default)]
+    struct MockPin {
+        high: bool,
+    }
+
+    impl OutputPin for MockPin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.high = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.high = true;
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct MockPwm {
+        duty: [u16; 2],
+    }
+
+    impl MotorPwm for MockPwm {
+        type Channel = usize;
+
+        fn max_duty(&self) -> u16 {
+            1000
+        }
+
+        fn set_duty(&mut self, channel: usize, duty: u16) {
+            self.duty[channel] = duty;
+        }
+    }
+
+    type MockDriver = DualMotorDriver<MockPwm, MockPin, MockPin>;
+
+    fn mock_driver() -> MockDriver {
+        DualMotorDriver::new(
+            MockPwm::default(),
+            Motor::new(MockPin::default(), 0),
+            Motor::new(MockPin::default(), 1),
+            MockPin::default(),
+        )
+    }
 
     #[test]
     fn it_works() {
         assert!(true)
     }
+
+    #[test]
+    fn motor_speed_maps_to_duty_and_direction() {
+        let mut motors = mock_driver();
+        motors.set(64, -128).unwrap();
+        assert_eq!(motors.pwm().duty, [500, 1000]);
+        let (_, left, right, _) = motors.release();
+        assert!(left.high);
+        assert!(!right.high);
+    }
+
+    #[test]
+    fn motor_inversion_flips_direction() {
+        let mut motors = mock_driver();
+        motors.set_inverted(true, false);
+        motors.set(10, 10).unwrap();
+        let (_, left, right, _) = motors.release();
+        assert!(!left.high);
+        assert!(right.high);
+    }
+
+    #[test]
+    fn motor_brake_and_coast() {
+        let mut motors = mock_driver();
+        motors.set(100, 100).unwrap();
+        motors.brake().unwrap();
+        assert_eq!(motors.pwm().duty, [0, 0]);
+        motors.set(100, 100).unwrap();
+        motors.coast().unwrap();
+        assert_eq!(motors.pwm().duty, [0, 0]);
+        let (_, _, _, stby) = motors.release();
+        // Standby is active low
+        assert!(!stby.high);
+    }
+
+    const LIMITS: RampLimits = RampLimits {
+        accel: 10,
+        decel: 20,
+        reversal: 30,
+    };
+
+    #[test]
+    fn ramp_accelerates_and_decelerates() {
+        let mut ramp = Ramp::new(LIMITS);
+        ramp.set_target(25);
+        assert_eq!(ramp.step(), 10);
+        assert_eq!(ramp.step(), 20);
+        assert_eq!(ramp.step(), 25);
+        assert!(ramp.is_settled());
+        ramp.set_target(0);
+        assert_eq!(ramp.step(), 5);
+        assert_eq!(ramp.step(), 0);
+    }
+
+    #[test]
+    fn ramp_reverses_through_standstill() {
+        let mut ramp = Ramp::new(LIMITS);
+        ramp.set_target(127);
+        while !ramp.is_settled() {
+            ramp.step();
+        }
+        ramp.set_target(-128);
+        assert_eq!(ramp.step(), 97);
+        for _ in 0..3 {
+            ramp.step();
+        }
+        assert_eq!(ramp.current(), 7);
+        // Doesn't overshoot zero, accelerates from there
+        assert_eq!(ramp.step(), 0);
+        assert_eq!(ramp.step(), -10);
+    }
+
+    #[test]
+    fn telemetry_encoding() {
+        let telemetry = Telemetry {
+            duty_left: 0x0102,
+            duty_right: 0x0304,
+            reverse_left: false,
+            reverse_right: true,
+            standby: true,
+            uptime_s: 0x05060708,
+            battery_mv: 7400,
+            faults: Faults::FAILSAFE,
+        };
+        assert_eq!(
+            telemetry.encode(),
+            [1, 0x02, 0x01, 0x04, 0x03, 0b110, 0x08, 0x07, 0x06, 0x05, 0xe8, 0x1c, 0x01, 0x00]
+        );
+    }
+
+    const BATTERY: BatteryConfig = BatteryConfig {
+        divider_num: 3,
+        divider_den: 1,
+        cells: 2,
+        curve: LI_ION_CURVE,
+    };
+
+    #[test]
+    fn battery_voltage_from_sample() {
+        // Half of the 3.6V full scale at the pin
+        assert_eq!(BATTERY.battery_mv(2048), 5400);
+        assert_eq!(BATTERY.battery_mv(-3), 0);
+    }
+
+    #[test]
+    fn battery_percent_interpolates_curve() {
+        assert_eq!(BATTERY.percent(8600), 100);
+        assert_eq!(BATTERY.percent(8400), 100);
+        assert_eq!(BATTERY.percent(7700), 57);
+        assert_eq!(BATTERY.percent(7600), 50);
+        assert_eq!(BATTERY.percent(6000), 0);
+    }
+
+    #[test]
+    fn gatt_security_mode_and_uuid() {
+        let open = SecurityMode::Open.raw();
+        assert_eq!((open.sm(), open.lv()), (1, 1));
+        let no_access = SecurityMode::NoAccess.raw();
+        assert_eq!((no_access.sm(), no_access.lv()), (0, 0));
+        let mitm = SecurityMode::EncryptedMitm.raw();
+        assert_eq!((mitm.sm(), mitm.lv()), (1, 3));
+
+        let uuid = Uuid::Vendor {
+            uuid_type: 2,
+            uuid: 0x0001,
+        }
+        .raw();
+        assert_eq!((uuid.type_, uuid.uuid), (2, 0x0001));
+        let uuid = Uuid::Sig(0x180F).raw();
+        assert_eq!((uuid.type_, uuid.uuid), (1, 0x180F));
+
+        let base = [0xAA; 16];
+        let full = uuid128(&base, 0x1234);
+        assert_eq!(&full[..12], &base[..12]);
+        assert_eq!(&full[12..], &[0x34, 0x12, 0xAA, 0xAA]);
+    }
+
+    #[test]
+    fn gatt_characteristic_props() {
+        let builder = CharacteristicBuilder::new(1, Uuid::Sig(0x2A19))
+            .write()
+            .reliable_write();
+        let props = builder.char_props();
+        assert_eq!((props.write(), props.read(), props.notify()), (1, 0, 0));
+        let ext_props = builder.char_ext_props();
+        assert_eq!((ext_props.reliable_wr(), ext_props.wr_aux()), (1, 0));
+
+        let ext_props = CharacteristicBuilder::new(1, Uuid::Sig(0x2A19)).char_ext_props();
+        assert_eq!(ext_props.reliable_wr(), 0);
+    }
+
+    #[test]
+    fn adv_data_encoding() {
+        const DATA: AdvertisingData = AdvertisingData::new()
+            .flags(FLAG_LE_GENERAL_DISCOVERABLE | FLAG_BR_EDR_NOT_SUPPORTED)
+            .short_name("Rusty")
+            .tx_power(-4)
+            .appearance(0x0200);
+        assert_eq!(
+            DATA.as_bytes(),
+            &[
+                2, 0x01, 0x06, 6, 0x08, b'R', b'u', b's', b't', b'y', 2, 0x0A, 0xFC, 3, 0x19, 0x00,
+                0x02
+            ]
+        );
+
+        let data = AdvertisingData::new()
+            .services_16(true, &[0x180F, 0x180A])
+            .manufacturer_data(0x0059, &[0xAB]);
+        assert_eq!(
+            data.as_bytes(),
+            &[5, 0x03, 0x0F, 0x18, 0x0A, 0x18, 4, 0xFF, 0x59, 0x00, 0xAB]
+        );
+
+        let uuid = [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D,
+            0x0E, 0x0F,
+        ];
+        let data = AdvertisingData::new().services_128(true, &[uuid]);
+        assert_eq!(data.len(), 18);
+        assert_eq!(&data.as_bytes()[..2], &[17, 0x07]);
+        assert_eq!(&data.as_bytes()[2..], &uuid);
+    }
+
+    #[test]
+    fn conn_params_units() {
+        let params = ConnParams {
+            interval: 24,
+            latency: 0,
+            timeout: 400,
+        };
+        assert_eq!(params.interval_us(), 30_000);
+        assert_eq!(params.timeout_ms(), 4000);
+    }
+
+    #[test]
+    fn bond_store_encoding_and_eviction() {
+        let bond = |n: u8| Bond {
+            addr_type: 1,
+            addr: [n; 6],
+            irk: [0x11; 16],
+            ltk: [n; 16],
+            ltk_len: 16,
+            lesc: true,
+            auth: true,
+            ediv: 0,
+            rand: [0; 8],
+        };
+        assert!(Bond::decode(&[0xff; Bond::LEN]).is_none());
+        assert!(Bond::decode(&bond(1).encode()) == Some(bond(1)));
+
+        let mut store = BondStore::new();
+        for n in 0..MAX_BONDS as u8 + 1 {
+            store.insert(bond(n));
+        }
+        // Oldest one evicted
+        assert_eq!(store.len(), MAX_BONDS);
+        assert!(store.find_by_addr(1, &[0; 6]).is_none());
+        assert!(store.find_by_addr(1, &[1; 6]).is_some());
+        // Re-bonding moves the bond to the end
+        store.insert(bond(1));
+        assert_eq!(store.len(), MAX_BONDS);
+        assert!(store.iter().last() == Some(&bond(1)));
+
+        let mut words = [0u32; MAX_BONDS * Bond::WORDS];
+        let len = store.encode(&mut words);
+        assert_eq!(len, MAX_BONDS * Bond::WORDS);
+        let mut bytes = [0xffu8; (MAX_BONDS + 1) * Bond::LEN];
+        for (chunk, word) in bytes.chunks_mut(4).zip(words.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        let decoded = BondStore::decode(&bytes);
+        assert!(decoded.iter().eq(store.iter()));
+
+        let mut page = [0xffu8; 16];
+        assert_eq!(flash::page_seq(&page, bond::PAGE_MAGIC), None);
+        let header = flash::page_header(bond::PAGE_MAGIC, 7);
+        for (chunk, word) in page.chunks_mut(4).zip(header.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        assert_eq!(flash::page_seq(&page, bond::PAGE_MAGIC), Some(7));
+        // Pages of another kind aren't taken for bonds
+        assert_eq!(flash::page_seq(&page, settings::PAGE_MAGIC), None);
+        page[0] ^= 1;
+        assert_eq!(flash::page_seq(&page, bond::PAGE_MAGIC), None);
+    }
+
+    #[test]
+    fn settings_validation() {
+        let mut settings = Settings::default();
+        settings.set(Key::MaxSpeed, &[100]).unwrap();
+        assert_eq!(settings.max_speed, 100);
+        assert!(settings.set(Key::MaxSpeed, &[128]) == Err(SettingsError::OutOfRange));
+        assert!(settings.set(Key::PwmFreq, &[1, 2]) == Err(SettingsError::InvalidLength));
+        assert!(settings.set(Key::RampLimits, &[1, 0, 1]) == Err(SettingsError::OutOfRange));
+        settings.set(Key::MotorInversion, &[2]).unwrap();
+        assert!(!settings.invert_left && settings.invert_right);
+        settings.set(Key::DeviceName, b"Rover 1").unwrap();
+        assert_eq!(settings.device_name.as_str(), "Rover 1");
+        assert!(settings.set(Key::DeviceName, &[0xff]) == Err(SettingsError::OutOfRange));
+        assert_eq!(settings.device_name.as_str(), "Rover 1");
+
+        let addr = [0xb2, 0xa1, 0x33, 0x44, 0x55, 0xc6];
+        assert_eq!(default_device_name(&addr).as_str(), "RustyRover-A1B2");
+    }
+
+    #[test]
+    fn settings_page_log() {
+        let mut page = [0xffu8; 128];
+        assert!(flash::page_seq(&page, settings::PAGE_MAGIC).is_none());
+        let header = flash::page_header(settings::PAGE_MAGIC, 7);
+        page[..4].copy_from_slice(&header[0].to_le_bytes());
+        page[4..8].copy_from_slice(&header[1].to_le_bytes());
+        assert_eq!(flash::page_seq(&page, settings::PAGE_MAGIC), Some(7));
+
+        let mut offset = 8;
+        let mut append = |page: &mut [u8], key: Key, value: &[u8]| {
+            let mut words = [0u32; MAX_RECORD_WORDS];
+            let len = encode_record(key, value, &mut words);
+            for word in &words[..len] {
+                page[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+                offset += 4;
+            }
+            offset
+        };
+        append(&mut page, Key::MaxSpeed, &[50]);
+        append(&mut page, Key::DeviceName, b"Rover");
+        // Later records override earlier ones
+        let end = append(&mut page, Key::MaxSpeed, &[60]);
+        // The CRC catches the corrupted name. Its value follows the page
+        // header (8 bytes), the first record (12) and its own header (4).
+        page[24] ^= 1;
+
+        let mut settings = Settings::default();
+        assert_eq!(settings.read_page(&page), end);
+        assert_eq!(settings.max_speed, 60);
+        assert_eq!(settings.device_name.as_str(), "");
+        assert!(settings.pwm_freq_hz == Settings::DEFAULT.pwm_freq_hz);
+    }
+
+    #[test]
+    fn config_command_parsing() {
+        assert!(Command::parse(&[0x01, 3]).ok() == Some(Command::Get(Key::MaxSpeed)));
+        match Command::parse(&[0x02, 5, 0xe8, 0x03, 0, 0]) {
+            Ok(Command::Set(Key::FailsafeTimeout, value)) => {
+                assert_eq!(value.as_slice(), &[0xe8, 3, 0, 0])
+            }
+            _ => panic!("Set not parsed"),
+        }
+        assert!(Command::parse(&[0x03]).ok() == Some(Command::Commit));
+        assert!(Command::parse(&[0x04]).ok() == Some(Command::ResetDefaults));
+
+        let status = |data: &[u8]| Command::parse(data).err().map(|reply| reply.status());
+        assert_eq!(status(&[0x09]), Some(Status::UnknownCommand as u8));
+        assert_eq!(status(&[0x01, 0x42]), Some(Status::UnknownKey as u8));
+        assert_eq!(status(&[0x01]), Some(Status::InvalidLength as u8));
+        assert_eq!(status(&[0x03, 0]), Some(Status::InvalidLength as u8));
+        assert_eq!(status(&[0x02; 24]), Some(Status::InvalidLength as u8));
+        assert_eq!(status(&[]), Some(Status::InvalidLength as u8));
+
+        let reply = Reply::with_value(&[100]);
+        assert_eq!(
+            reply.as_bytes(),
+            &[Opcode::Get as u8, Status::Success as u8, 100]
+        );
+    }
+
+    #[test]
+    fn fault_log_keeps_newest() {
+        let mut fault = Fault::with_data(FaultKind::SoftDevice, &[1, 0x0002_3456, 0xdead_beef]);
+        fault.seq = 0x1234;
+        fault.reset_reason = 0x8;
+        fault.set_text(format_args!("line {}", 42));
+        let record = fault.encode();
+        assert_eq!(record.len(), Fault::LEN);
+        assert_eq!(record[..8], [Fault::MARKER, 2, 0x34, 0x12, 8, 0, 0, 0]);
+        assert_eq!(record[12..16], [0x56, 0x34, 0x02, 0x00]);
+        let decoded = Fault::decode(&record).unwrap();
+        assert!(decoded == fault);
+        assert_eq!(decoded.text(), "line 42");
+
+        let mut page = [0xffu8; PAGE_HEADER_LEN + (MAX_FAULTS + 4) * Fault::LEN];
+        assert!(FaultLog::read(&page).is_empty());
+        let header = flash::page_header(fault_log::PAGE_MAGIC, 3);
+        page[..4].copy_from_slice(&header[0].to_le_bytes());
+        page[4..8].copy_from_slice(&header[1].to_le_bytes());
+        let records = page[PAGE_HEADER_LEN..].chunks_mut(Fault::LEN);
+        for (seq, record) in records.take(MAX_FAULTS + 2).enumerate() {
+            let mut fault = fault;
+            fault.seq = seq as u16;
+            record.copy_from_slice(&fault.encode());
+        }
+        let log = FaultLog::read(&page);
+        assert_eq!(log.len(), MAX_FAULTS);
+        assert_eq!(log.iter().next().map(|f| f.seq), Some(2));
+        assert_eq!(
+            log.iter().last().map(|f| f.seq),
+            Some(MAX_FAULTS as u16 + 1)
+        );
+
+        // Records of an older format aren't read
+        page[PAGE_HEADER_LEN] = 0xfa;
+        assert!(FaultLog::read(&page).is_empty());
+        // Neither are pages without header
+        page[0] ^= 1;
+        assert!(FaultLog::read(&page).is_empty());
+    }
+
+    #[test]
+    fn fault_text_is_truncated() {
+        let mut fault = Fault::new(FaultKind::Panic);
+        fault.set_text(format_args!("{:0>56}", "\u{e9}"));
+        assert_eq!(fault.text().len(), TEXT_LEN - 1);
+        fault.set_text(format_args!("{:0>55}", "\u{e9}"));
+        assert_eq!(fault.text().len(), TEXT_LEN);
+        assert!(fault.text().ends_with('\u{e9}'));
+    }
+
+    #[test]
+    fn crash_survives_until_taken() {
+        crash::take(0);
+        assert!(crash::take(0).is_none());
+        assert_eq!(
+            crash::take(crash::RESET_WATCHDOG).map(|f| f.kind),
+            Some(FaultKind::Reset)
+        );
+
+        crash::record(&Fault::with_data(FaultKind::SoftDevice, &[1]));
+        crash::record(&Fault::new(FaultKind::HardFault));
+        let fault = crash::take(crash::RESET_SOFT).unwrap();
+        assert_eq!(fault.kind, FaultKind::SoftDevice);
+        assert_eq!(fault.data[0], 1);
+        assert_eq!(fault.reset_reason, crash::RESET_SOFT);
+        assert!(crash::take(0).is_none());
+    }
+
+    #[test]
+    fn monotonic_ticks() {
+        const WRAP: u64 = 1 << 24;
+        assert_eq!(monotonic::ticks(0, false, 0), 0);
+        // The counter wraps from its last value to the next overflow
+        assert_eq!(monotonic::ticks(0, false, 0xFF_FFFF), WRAP - 1);
+        assert_eq!(monotonic::ticks(1, false, 0), WRAP);
+        assert_eq!(monotonic::ticks(2, false, 3), 2 * WRAP + 3);
+        // Wrapped, but the overflow interrupt didn't run yet
+        assert_eq!(monotonic::ticks(0, true, 0), WRAP);
+        assert_eq!(monotonic::ticks(1, true, 5), 2 * WRAP + 5);
+        // Bits above the 24 bit counter are ignored
+        assert_eq!(monotonic::ticks(0, false, 0xFF00_0001), 1);
+    }
 }